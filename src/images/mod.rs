@@ -0,0 +1,2 @@
+pub mod volume;
+pub mod warp;
@@ -0,0 +1,264 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+
+use byteorder::BigEndian;
+use byteorder::ByteOrder;
+use byteorder::LittleEndian;
+use byteorder::ReadBytesExt;
+use byteorder::WriteBytesExt;
+
+use crate::gis::load_origin;
+use crate::gis::save_origin;
+use crate::gis::Point3dd;
+use crate::gis::K;
+use crate::grid::Grid;
+
+// Storage type of the voxels in files, values are always handled as f64.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VoxelType {
+    U8,
+    S8,
+    U16,
+    S16,
+    U32,
+    S32,
+    Float,
+    Double,
+}
+
+impl VoxelType {
+    fn from_gis(name: &str) -> Option<Self> {
+        match name {
+            "U8" => Some(VoxelType::U8),
+            "S8" => Some(VoxelType::S8),
+            "U16" => Some(VoxelType::U16),
+            "S16" => Some(VoxelType::S16),
+            "U32" => Some(VoxelType::U32),
+            "S32" => Some(VoxelType::S32),
+            "FLOAT" => Some(VoxelType::Float),
+            "DOUBLE" => Some(VoxelType::Double),
+            _ => None,
+        }
+    }
+
+    fn gis_name(self) -> &'static str {
+        match self {
+            VoxelType::U8 => "U8",
+            VoxelType::S8 => "S8",
+            VoxelType::U16 => "U16",
+            VoxelType::S16 => "S16",
+            VoxelType::U32 => "U32",
+            VoxelType::S32 => "S32",
+            VoxelType::Float => "FLOAT",
+            VoxelType::Double => "DOUBLE",
+        }
+    }
+
+    pub fn is_integer(self) -> bool {
+        !matches!(self, VoxelType::Float | VoxelType::Double)
+    }
+
+    pub fn size(self) -> usize {
+        match self {
+            VoxelType::U8 | VoxelType::S8 => 1,
+            VoxelType::U16 | VoxelType::S16 => 2,
+            VoxelType::U32 | VoxelType::S32 | VoxelType::Float => 4,
+            VoxelType::Double => 8,
+        }
+    }
+
+    pub(crate) fn read<B: ByteOrder, R: Read>(self, reader: &mut R) -> Result<f64, Box<dyn Error>> {
+        Ok(match self {
+            VoxelType::U8 => f64::from(reader.read_u8()?),
+            VoxelType::S8 => f64::from(reader.read_i8()?),
+            VoxelType::U16 => f64::from(reader.read_u16::<B>()?),
+            VoxelType::S16 => f64::from(reader.read_i16::<B>()?),
+            VoxelType::U32 => f64::from(reader.read_u32::<B>()?),
+            VoxelType::S32 => f64::from(reader.read_i32::<B>()?),
+            VoxelType::Float => f64::from(reader.read_f32::<B>()?),
+            VoxelType::Double => reader.read_f64::<B>()?,
+        })
+    }
+
+    // Integer values are rounded, and saturate at the bounds of the type.
+    pub(crate) fn write<B: ByteOrder, W: Write>(
+        self,
+        writer: &mut W,
+        value: f64,
+    ) -> Result<(), Box<dyn Error>> {
+        let v = value.round();
+        match self {
+            VoxelType::U8 => writer.write_u8(v as u8)?,
+            VoxelType::S8 => writer.write_i8(v as i8)?,
+            VoxelType::U16 => writer.write_u16::<B>(v as u16)?,
+            VoxelType::S16 => writer.write_i16::<B>(v as i16)?,
+            VoxelType::U32 => writer.write_u32::<B>(v as u32)?,
+            VoxelType::S32 => writer.write_i32::<B>(v as i32)?,
+            VoxelType::Float => writer.write_f32::<B>(value as f32)?,
+            VoxelType::Double => writer.write_f64::<B>(value)?,
+        }
+
+        Ok(())
+    }
+}
+
+// Scalar image sampled on a regular grid, x varying fastest.
+#[derive(Clone, Debug)]
+pub struct Volume {
+    grid: Grid,
+    voxel_type: VoxelType,
+    data: Vec<f64>,
+}
+
+impl Volume {
+    pub fn new(grid: Grid, voxel_type: VoxelType, data: Vec<f64>) -> Result<Self, Box<dyn Error>> {
+        if data.len() != grid.len() {
+            return Err(format!(
+                "Expected {} values for a {:?} grid, got {}",
+                grid.len(),
+                grid.dimensions,
+                data.len()
+            )
+            .into());
+        }
+
+        Ok(Volume {
+            grid,
+            voxel_type,
+            data,
+        })
+    }
+
+    pub fn filled(grid: Grid, voxel_type: VoxelType, value: f64) -> Self {
+        let data = vec![value; grid.len()];
+
+        Volume {
+            grid,
+            voxel_type,
+            data,
+        }
+    }
+
+    pub fn grid(&self) -> &Grid {
+        &self.grid
+    }
+
+    pub fn voxel_type(&self) -> VoxelType {
+        self.voxel_type
+    }
+
+    pub fn data(&self) -> &[f64] {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut [f64] {
+        &mut self.data
+    }
+
+    pub fn get(&self, i: usize, j: usize, k: usize) -> f64 {
+        self.data[self.grid.index(i, j, k)]
+    }
+
+    // Position in voxels of p, expressed in millimeters.
+    pub fn voxel_position(&self, p: &Point3dd) -> Point3dd {
+        let g = &self.grid;
+        let mut v = Point3dd([0.; K]);
+        for k in 0..K {
+            v.0[k] = (p[k] - g.origin[k]) / g.spacing[k];
+        }
+
+        v
+    }
+
+    pub fn load_gis(basename: &str) -> Result<Self, Box<dyn Error>> {
+        let filename = format!("{}.dim", basename);
+        let mut file_in = BufReader::new(File::open(&filename)?);
+
+        let mut string = String::new();
+        file_in.read_to_string(&mut string)?;
+
+        let mut iter = string.lines();
+        let dimensions = match iter.next() {
+            Some(line) => line
+                .split_whitespace()
+                .map(|value| value.parse::<usize>())
+                .collect::<Result<Vec<_>, _>>()?,
+            None => return Err(format!("{}: empty header", filename).into()),
+        };
+
+        let mut size = [1; K];
+        for (k, d) in dimensions.iter().take(K).enumerate() {
+            size[k] = *d;
+        }
+        if dimensions.iter().skip(K).any(|&d| d > 1) {
+            return Err(format!("{}: only 3D volumes are supported", filename).into());
+        }
+
+        let mut spacing = Point3dd([1.; K]);
+        let mut voxel_type = None;
+        let mut big_endian = false;
+
+        for line in iter {
+            let values = line.split_whitespace().collect::<Vec<_>>();
+
+            for i in 0..(values.len() / 2) {
+                let (param, value) = (values[i * 2], values[i * 2 + 1]);
+                match param {
+                    "-type" => voxel_type = VoxelType::from_gis(value),
+                    "-dx" => spacing.0[0] = value.parse::<f64>()?,
+                    "-dy" => spacing.0[1] = value.parse::<f64>()?,
+                    "-dz" => spacing.0[2] = value.parse::<f64>()?,
+                    "-bo" => big_endian = value == "ABCD",
+                    "-om" if value != "binar" => {
+                        return Err(format!("{}: unsupported mode {}", filename, value).into())
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        let voxel_type = match voxel_type {
+            Some(t) => t,
+            None => return Err(format!("{}: missing or unsupported -type", filename).into()),
+        };
+
+        let grid = Grid::new(load_origin(basename)?, spacing, size);
+        let mut file_in = BufReader::new(File::open(format!("{}.ima", basename))?);
+        let mut data = Vec::with_capacity(grid.len());
+
+        for _ in 0..grid.len() {
+            data.push(if big_endian {
+                voxel_type.read::<BigEndian, _>(&mut file_in)?
+            } else {
+                voxel_type.read::<LittleEndian, _>(&mut file_in)?
+            });
+        }
+
+        Self::new(grid, voxel_type, data)
+    }
+
+    pub fn save_gis(&self, basename: &str) -> Result<(), Box<dyn Error>> {
+        let d = &self.grid.dimensions;
+        let s = &self.grid.spacing;
+
+        let mut file_out = BufWriter::new(File::create(format!("{}.dim", basename))?);
+        writeln!(file_out, "{} {} {} 1", d[0], d[1], d[2])?;
+        writeln!(file_out, "-type {}", self.voxel_type.gis_name())?;
+        writeln!(file_out, "-dx {} -dy {} -dz {} -dt 1", s[0], s[1], s[2])?;
+        writeln!(file_out, "-bo DCBA")?;
+        writeln!(file_out, "-om binar")?;
+
+        let mut file_out = BufWriter::new(File::create(format!("{}.ima", basename))?);
+        for v in &self.data {
+            self.voxel_type
+                .write::<LittleEndian, _>(&mut file_out, *v)?;
+        }
+        file_out.flush()?;
+
+        save_origin(basename, &self.grid.origin)
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+use crate::gis::Point3dd;
+use crate::gis::K;
+use crate::grid::Grid;
+use crate::Transform;
+
+use super::volume::Volume;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interpolation {
+    Nearest,
+    Trilinear,
+    Cubic, // Catmull-Rom spline, which goes through the voxel values
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LabelInterpolation {
+    Nearest,
+    // Most frequent label among n^3 sub-samples of each target voxel, ties
+    // going to the smallest label. The background takes part in the vote.
+    Majority(usize),
+}
+
+// Voxel indices along an axis, with their interpolation weights.
+type AxisWeights = Vec<(usize, f64)>;
+
+impl Volume {
+    // Value at p, expressed in millimeters, None outside of the volume.
+    pub fn sample(&self, p: &Point3dd, interpolation: Interpolation) -> Option<f64> {
+        if p.is_nan() {
+            return None;
+        }
+
+        let v = self.voxel_position(p);
+        let d = &self.grid().dimensions;
+
+        match interpolation {
+            Interpolation::Nearest => {
+                let mut i = [0; K];
+                for k in 0..K {
+                    let r = v[k].round();
+                    if r < 0.0 || r >= d[k] as f64 {
+                        return None;
+                    }
+                    i[k] = r as usize;
+                }
+
+                Some(self.get(i[0], i[1], i[2]))
+            }
+            Interpolation::Trilinear => {
+                let weights = self.weights(&v, |t| vec![1.0 - t, t], 0)?;
+                Some(self.convolve(&weights))
+            }
+            Interpolation::Cubic => {
+                let weights = self.weights(&v, catmull_rom, 1)?;
+                Some(self.convolve(&weights))
+            }
+        }
+    }
+
+    // Per axis, the voxel indices, clamped to the volume, and their weights.
+    // `before` is the number of voxels used before the one containing v.
+    fn weights<F>(&self, v: &Point3dd, kernel: F, before: usize) -> Option<Vec<AxisWeights>>
+    where
+        F: Fn(f64) -> Vec<f64>,
+    {
+        let d = &self.grid().dimensions;
+        let mut weights = vec![];
+
+        for k in 0..K {
+            let max = (d[k] - 1) as f64;
+            if !(v[k] >= 0.0 && v[k] <= max) {
+                return None;
+            }
+
+            let base = v[k].floor();
+            let axis = kernel(v[k] - base)
+                .into_iter()
+                .enumerate()
+                .map(|(i, w)| {
+                    let index = base as i64 + i as i64 - before as i64;
+                    (index.max(0).min(max as i64) as usize, w)
+                })
+                .collect::<Vec<_>>();
+
+            weights.push(axis);
+        }
+
+        Some(weights)
+    }
+
+    fn convolve(&self, weights: &[AxisWeights]) -> f64 {
+        let mut value = 0.0;
+        for (k, wz) in &weights[2] {
+            for (j, wy) in &weights[1] {
+                for (i, wx) in &weights[0] {
+                    value += wx * wy * wz * self.get(*i, *j, *k);
+                }
+            }
+        }
+
+        value
+    }
+}
+
+fn catmull_rom(t: f64) -> Vec<f64> {
+    let t2 = t * t;
+    let t3 = t2 * t;
+
+    vec![
+        (-t3 + 2.0 * t2 - t) / 2.0,
+        (3.0 * t3 - 5.0 * t2 + 2.0) / 2.0,
+        (-3.0 * t3 + 4.0 * t2 + t) / 2.0,
+        (t3 - t2) / 2.0,
+    ]
+}
+
+// Resample `source` on the target grid.
+//
+// This pulls values back: `transform` has to map positions of the target
+// space into the space of the source volume, which is the inverse of the
+// transform used to move points from the source to the target. Voxels
+// mapped to NaN or outside of the source get the background value.
+pub fn warp(
+    source: &Volume,
+    transform: &dyn Transform,
+    target: &Grid,
+    interpolation: Interpolation,
+    background: f64,
+) -> Volume {
+    let mut result = Volume::filled(target.clone(), source.voxel_type(), background);
+    let d = target.dimensions;
+    let mut index = 0;
+
+    for z in 0..d[2] {
+        for y in 0..d[1] {
+            let row = (0..d[0])
+                .map(|x| target.position(x, y, z))
+                .collect::<Vec<_>>();
+
+            for p in transform.transform_batch(&row) {
+                if let Some(v) = source.sample(&p, interpolation) {
+                    result.data_mut()[index] = v;
+                }
+                index += 1;
+            }
+        }
+    }
+
+    result
+}
+
+// Same as `warp`, for volumes of integer labels, which are never mixed.
+pub fn warp_labels(
+    source: &Volume,
+    transform: &dyn Transform,
+    target: &Grid,
+    interpolation: LabelInterpolation,
+    background: f64,
+) -> Result<Volume, Box<dyn Error>> {
+    if !source.voxel_type().is_integer() {
+        return Err(format!(
+            "Labels are expected to be integers, got {:?}",
+            source.voxel_type()
+        )
+        .into());
+    }
+
+    let n = match interpolation {
+        LabelInterpolation::Nearest => {
+            return Ok(warp(
+                source,
+                transform,
+                target,
+                Interpolation::Nearest,
+                background,
+            ))
+        }
+        LabelInterpolation::Majority(n) => n.max(1),
+    };
+
+    // Sub-samples offsets, centered in the target voxel.
+    let mut offsets = vec![];
+    for z in 0..n {
+        for y in 0..n {
+            for x in 0..n {
+                let mut o = Point3dd([0.; K]);
+                for (k, i) in [x, y, z].iter().enumerate() {
+                    o.0[k] = ((*i as f64 + 0.5) / n as f64 - 0.5) * target.spacing[k];
+                }
+                offsets.push(o);
+            }
+        }
+    }
+
+    let mut result = Volume::filled(target.clone(), source.voxel_type(), background);
+    let d = target.dimensions;
+    let mut votes = HashMap::new();
+    let mut index = 0;
+
+    for z in 0..d[2] {
+        for y in 0..d[1] {
+            for x in 0..d[0] {
+                let c = target.position(x, y, z);
+                let positions = offsets
+                    .iter()
+                    .map(|o| Point3dd([c[0] + o[0], c[1] + o[1], c[2] + o[2]]))
+                    .collect::<Vec<_>>();
+
+                votes.clear();
+                for p in transform.transform_batch(&positions) {
+                    let label = source
+                        .sample(&p, Interpolation::Nearest)
+                        .unwrap_or(background);
+                    *votes.entry(label as i64).or_insert(0usize) += 1;
+                }
+
+                let winner = votes
+                    .iter()
+                    .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0)))
+                    .map(|(label, _)| *label as f64);
+                result.data_mut()[index] = winner.unwrap_or(background);
+                index += 1;
+            }
+        }
+    }
+
+    Ok(result)
+}
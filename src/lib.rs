@@ -2,10 +2,30 @@
 #[macro_use]
 extern crate measure_time;
 
+mod images;
 mod transforms;
 
 pub use transforms::affine;
+pub use transforms::bounds;
+pub use transforms::chain;
+pub use transforms::compose;
+pub use transforms::convention;
+pub use transforms::flirt;
+pub use transforms::fnirt;
 pub use transforms::gis;
+pub use transforms::grid;
+pub use transforms::itk;
+pub use transforms::metaimage;
+pub use transforms::nifti;
+pub use transforms::nrrd;
+pub use transforms::oriented;
+pub use transforms::pyramid;
+pub use transforms::region;
+pub use transforms::registry;
+pub use transforms::Transform;
+
+pub use images::volume;
+pub use images::warp;
 
 #[cfg(test)]
 mod nice_float;
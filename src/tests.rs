@@ -1,16 +1,43 @@
 use super::*;
 
 use std::error::Error;
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
 
+use byteorder::BigEndian;
+use byteorder::ByteOrder;
+use byteorder::LittleEndian;
 use log::info;
 use log::trace;
 use log::warn;
 use serde::Deserialize;
 use serde::Serialize;
 
+use affine::AffineTransform;
+use bounds::DisplacementPyramid;
+use chain::TransformChain;
+use convention::Convention;
+use convention::Converted;
+use flirt::Geometry;
+use fnirt::SplineField;
+use gis::FieldMode;
 use gis::GISTransform;
 use gis::Point3dd;
+use grid::Grid;
 use nice_float::NiceFloat;
+use nrrd::Encoding;
+use oriented::OrientedField;
+use pyramid::FieldPyramid;
+use region::BoundingBox;
+use region::Sampling;
+use region::Sphere;
+use registry::SpaceRegistry;
+use volume::Volume;
+use volume::VoxelType;
+use warp::Interpolation;
+use warp::LabelInterpolation;
 
 const PRECISION: f64 = 1E6;
 
@@ -26,35 +53,33 @@ struct PointsResponse {
     target_points: Vec<Vec<NiceFloat>>,
 }
 
-fn init() -> Result<(String, String, GISTransform), Box<dyn Error>> {
+// The reference deformation map is expected to be the only transform
+// registered in data/registry.json, between the spaces known to the
+// reference implementation.
+fn init() -> Result<(String, String, SpaceRegistry, Point3dd), Box<dyn Error>> {
     match pretty_env_logger::try_init() {
         _ => (), // Just ignore whatever is going on...
     }
 
-    // x y z
-    //448 x 486 x 403
+    let registry = registry::load_file("data/registry.json")?;
 
-    // 39d2e4cf-8979-9fb2-bc29-2a4ead14ae40 -> 23df7ce8-e405-bc31-3863-d543e3cc89e5
-    //  => full_cls_400um_border_default_acquisition_disco_analysis_displ_field_DISCO_DARTEL_20181004_reg_x4.ima
-    let source_space = "39d2e4cf-8979-9fb2-bc29-2a4ead14ae40".to_string();
-    let target_space = "23df7ce8-e405-bc31-3863-d543e3cc89e5".to_string();
-    let basename = "full_cls_400um_border_default_acquisition_disco_analysis_displ_field_DISCO_DARTEL_20181004_reg_x4";
-
-    let filename = format!("data/{}", basename);
+    let (source_space, target_space) = match registry.registered()[..] {
+        [(source, target)] => (source.to_string(), target.to_string()),
+        _ => return Err("Expected a single transform in data/registry.json".into()),
+    };
 
-    let gis = transforms::gis::load_file(&filename)?;
+    let extent = match registry.domain(&source_space, &target_space) {
+        Some(domain) => domain.extent(),
+        None => return Err(format!("No domain for {} -> {}", source_space, target_space).into()),
+    };
 
-    info!(
-        "dimensions: {:?}, {:?} [mm]",
-        gis.dimensions(),
-        gis.dimensions_mm()
-    );
-    Ok((source_space, target_space, gis))
+    info!("{} -> {}: {:?} [mm]", source_space, target_space, extent);
+    Ok((source_space, target_space, registry, extent))
 }
 
 #[allow(clippy::many_single_char_names)]
 fn compare_block(
-    gis: &GISTransform,
+    registry: &SpaceRegistry,
     source_space: String,
     target_space: String,
     x: (i32, i32),
@@ -81,17 +106,20 @@ fn compare_block(
     let client = reqwest::Client::new();
 
     let mut source_points = vec![];
-    let mut my_results = vec![];
     for z in values(z.0, z.1, step) {
         for y in values(y.0, y.1, step) {
             for x in values(x.0, x.1, step) {
-                let p = vec![x, y, z];
-                my_results.push(gis.deformation(&(&p).into()));
-                source_points.push(p);
+                source_points.push(vec![x, y, z]);
             }
         }
     }
 
+    let points = source_points
+        .iter()
+        .map(|p| p.into())
+        .collect::<Vec<Point3dd>>();
+    let my_results = registry.transform_points(&source_space, &target_space, &points)?;
+
     let param = ReqParam {
         source_space,
         target_space,
@@ -148,15 +176,13 @@ fn compare_block(
 
 #[test]
 fn check_diagonal() -> Result<(), Box<dyn Error>> {
-    let (source_space, target_space, gis) = init()?;
+    let (source_space, target_space, registry, extent) = init()?;
 
     let mut success = true;
     let mut prev = 0;
 
     // The diagonal cannot go further than the smallest dimension.
-    let max = gis.dimensions_mm()[0]
-        .min(gis.dimensions_mm()[1])
-        .min(gis.dimensions_mm()[2]) as i32;
+    let max = extent[0].min(extent[1]).min(extent[2]) as i32;
 
     for d in (0..max).step_by(10) {
         info!(
@@ -165,7 +191,7 @@ fn check_diagonal() -> Result<(), Box<dyn Error>> {
         );
 
         success = compare_block(
-            &gis,
+            &registry,
             source_space.clone(),
             target_space.clone(),
             (prev, d),
@@ -185,15 +211,13 @@ fn check_diagonal() -> Result<(), Box<dyn Error>> {
 
 #[test]
 fn check_interpolation_diagonal() -> Result<(), Box<dyn Error>> {
-    let (source_space, target_space, gis) = init()?;
+    let (source_space, target_space, registry, extent) = init()?;
 
     let mut success = true;
     let mut prev = 0;
 
     // The diagonal cannot go further than the smallest dimension.
-    let max = gis.dimensions_mm()[0]
-        .min(gis.dimensions_mm()[1])
-        .min(gis.dimensions_mm()[2]) as i32;
+    let max = extent[0].min(extent[1]).min(extent[2]) as i32;
 
     for d in (0..max).step_by(10) {
         info!(
@@ -207,7 +231,7 @@ fn check_interpolation_diagonal() -> Result<(), Box<dyn Error>> {
         );
 
         success = compare_block(
-            &gis,
+            &registry,
             source_space.clone(),
             target_space.clone(),
             (prev, prev + 1),
@@ -228,13 +252,13 @@ fn check_interpolation_diagonal() -> Result<(), Box<dyn Error>> {
 #[test]
 #[ignore]
 fn check_whole_space() -> Result<(), Box<dyn Error>> {
-    let (source_space, target_space, gis) = init()?;
+    let (source_space, target_space, registry, extent) = init()?;
 
     let mut success = true;
     let (mut z_prev, mut y_prev, mut x_prev) = (0, 0, 0);
-    let z_max = gis.dimensions_mm()[2] as i32;
-    let y_max = gis.dimensions_mm()[1] as i32;
-    let x_max = gis.dimensions_mm()[0] as i32;
+    let z_max = extent[2] as i32;
+    let y_max = extent[1] as i32;
+    let x_max = extent[0] as i32;
 
     for z in (0..z_max).step_by(10) {
         for y in (0..y_max).step_by(10) {
@@ -245,7 +269,7 @@ fn check_whole_space() -> Result<(), Box<dyn Error>> {
                 );
 
                 success = compare_block(
-                    &gis,
+                    &registry,
                     source_space.clone(),
                     target_space.clone(),
                     (x_prev, x),
@@ -269,7 +293,7 @@ fn check_whole_space() -> Result<(), Box<dyn Error>> {
 
 #[test]
 fn check_nan() -> Result<(), Box<dyn Error>> {
-    let (source_space, target_space, gis) = init()?;
+    let (source_space, target_space, registry, _) = init()?;
 
     let (z_prev, y_prev, x_prev) = (20, 20, 20);
     let (z, y, x) = (21, 21, 21);
@@ -280,7 +304,7 @@ fn check_nan() -> Result<(), Box<dyn Error>> {
     );
 
     let success = compare_block(
-        &gis,
+        &registry,
         source_space.clone(),
         target_space.clone(),
         (x_prev, x),
@@ -293,3 +317,1664 @@ fn check_nan() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+// Smooth field which does not depend on the reference data.
+fn synthetic_field(origin: Point3dd) -> GISTransform {
+    let grid = Grid::new(origin, Point3dd([0.5, 0.5, 0.5]), [12, 10, 8]);
+    let d = grid.dimensions;
+    let mut values = Vec::with_capacity(grid.len() * 3);
+
+    for z in 0..d[2] {
+        for y in 0..d[1] {
+            for x in 0..d[0] {
+                let p = grid.position(x, y, z);
+                values.push((0.5 * (0.3 * p[1]).sin()) as f32);
+                values.push((0.5 * (0.2 * p[2]).cos()) as f32);
+                values.push((0.02 * p[0] * p[1]) as f32);
+            }
+        }
+    }
+
+    GISTransform::from_grid(&grid, values).unwrap()
+}
+
+// Deterministic pseudo-random values in [0, 1), for randomized checks.
+fn random(state: &mut u64) -> f64 {
+    *state = state
+        .wrapping_mul(6_364_136_223_846_793_005)
+        .wrapping_add(1_442_695_040_888_963_407);
+
+    (*state >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// Random point of the box.
+fn random_point(state: &mut u64, b: &BoundingBox) -> Point3dd {
+    let mut p = Point3dd([0.; 3]);
+    for k in 0..3 {
+        p.0[k] = b.low[k] + random(state) * (b.high[k] - b.low[k]);
+    }
+
+    p
+}
+
+// The synthetic field, with a few control points set to NaN.
+fn holed_field(origin: Point3dd) -> GISTransform {
+    let field = synthetic_field(origin);
+    let grid = field.grid();
+    let d = grid.dimensions;
+    let mut values = Vec::with_capacity(grid.len() * 3);
+
+    for z in 0..d[2] {
+        for y in 0..d[1] {
+            for x in 0..d[0] {
+                let v = field.ctrl_point(x, y, z);
+                let hole = (x, y) == (3, 4) || (x, y, z) == (11, 9, 7);
+                for k in 0..3 {
+                    values.push(if hole { f32::NAN } else { v[k] });
+                }
+            }
+        }
+    }
+
+    GISTransform::from_grid(&grid, values).unwrap()
+}
+
+fn same_point(a: &Point3dd, b: &Point3dd, precision: f64) -> bool {
+    if a.is_nan() || b.is_nan() {
+        return a.is_nan() && b.is_nan();
+    }
+
+    (0..3).all(|k| (a[k] - b[k]).abs() <= precision)
+}
+
+// Rotated by 90 degrees around z, with 2mm voxels.
+fn rotated_to_world() -> AffineTransform {
+    AffineTransform::new(
+        [[0.0, -2.0, 0.0], [2.0, 0.0, 0.0], [0.0, 0.0, 2.0]],
+        [10.0, 20.0, 30.0],
+    )
+}
+
+// Path in the temporary directory, unique to the process and the call, so
+// that tests running in parallel do not collide. The files sharing its
+// name, whatever their extension, are removed when it goes out of scope,
+// such as the .ima, .dim and .minf files of a GIS basename, or the .raw
+// file of a .mhd header.
+struct TempPath(String);
+
+impl TempPath {
+    fn new(name: &str) -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path =
+            std::env::temp_dir().join(format!("mercator_{}_{}_{}", std::process::id(), n, name));
+
+        TempPath(path.to_string_lossy().to_string())
+    }
+}
+
+impl Deref for TempPath {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        Path::new(&self.0)
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        fn stem(name: &std::ffi::OsStr) -> String {
+            name.to_string_lossy()
+                .split('.')
+                .next()
+                .unwrap()
+                .to_string()
+        }
+
+        let path = Path::new(&self.0);
+        let (directory, name) = match (path.parent(), path.file_name()) {
+            (Some(d), Some(n)) => (d, stem(n)),
+            _ => return,
+        };
+
+        if let Ok(entries) = std::fs::read_dir(directory) {
+            for entry in entries.flatten() {
+                if stem(&entry.file_name()) == name {
+                    let _ = std::fs::remove_file(entry.path());
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn check_save_load() -> Result<(), Box<dyn Error>> {
+    let field = synthetic_field(Point3dd([-2.0, 1.0, 0.5]));
+    let basename = TempPath::new("check_save_load");
+
+    field.save(&basename)?;
+    let loaded = GISTransform::load_file(&basename)?;
+
+    assert_eq!(field.dimensions()[..3], loaded.dimensions()[..3]);
+    for p in &[
+        [-2.0, 1.0, 0.5],
+        [0.3, 2.7, 1.1],
+        [3.6, 5.4, 4.2],
+        [9.0, 9.0, 9.0],
+    ] {
+        let p = Point3dd(*p);
+        assert!(same_point(
+            &field.deformation(&p),
+            &loaded.deformation(&p),
+            0.0
+        ));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn check_minf() -> Result<(), Box<dyn Error>> {
+    let field = synthetic_field(Point3dd([-2.0, 1.0, 0.5]));
+    let basename = TempPath::new("check_minf");
+    let minf = format!("{}.ima.minf", &*basename);
+
+    // Attributes written by other tools are kept, whatever the origin.
+    let aims =
+        "attributes = {\n    'referentials' : [ 'Scanner-based anatomical coordinates' ],\n}\n";
+    std::fs::write(&minf, aims)?;
+
+    field.save(&basename)?;
+    assert!(std::fs::read_to_string(&minf)?.contains("'referentials'"));
+    assert!(same_point(
+        GISTransform::load_file(&basename)?.origin(),
+        field.origin(),
+        0.0
+    ));
+
+    let field = synthetic_field(Point3dd([0.0, 0.0, 0.0]));
+    field.save(&basename)?;
+    assert_eq!(std::fs::read_to_string(&minf)?, aims);
+
+    // A file holding only the origin is removed with it.
+    std::fs::remove_file(&minf)?;
+    synthetic_field(Point3dd([1.0, 0.0, 0.0])).save(&basename)?;
+    field.save(&basename)?;
+    assert!(!Path::new(&minf).exists());
+
+    Ok(())
+}
+
+#[test]
+fn check_compose() {
+    let field = synthetic_field(Point3dd([0.0, 0.0, 0.0]));
+    let grid = field.grid();
+    let composed = compose::compose(&field, &[], &field, &grid);
+    let d = grid.dimensions;
+
+    // Both are exact on the control points of the grid. Stay away from the
+    // borders, where NaN from outside of the field propagate.
+    for z in 3..d[2] - 3 {
+        for y in 3..d[1] - 3 {
+            for x in 3..d[0] - 3 {
+                let p = grid.position(x, y, z);
+                let expected = field.deformation(&field.deformation(&p));
+                assert!(same_point(&expected, &composed.deformation(&p), 1e-5));
+            }
+        }
+    }
+}
+
+#[test]
+fn check_bake() -> Result<(), Box<dyn Error>> {
+    // Trilinear interpolation of the displacement of an affine transform is exact.
+    let affine = AffineTransform::new(
+        [[1.1, 0.1, 0.0], [0.0, 0.9, 0.2], [0.05, 0.0, 1.0]],
+        [2.0, -1.0, 0.5],
+    );
+    let mut chain = TransformChain::new();
+    chain.push(Box::new(affine), false)?;
+
+    let extent = BoundingBox::new(Point3dd([0.0, 0.0, 0.0]), Point3dd([10.0, 8.0, 6.0]));
+    let (_, error) = chain.bake(&extent, Point3dd([1.0, 1.0, 1.0]), 1000);
+
+    assert_eq!(error.samples, 1000);
+    assert_eq!(error.nan_mismatch, 0);
+    assert!(error.max < 1e-4);
+
+    Ok(())
+}
+
+#[test]
+fn check_chain() -> Result<(), Box<dyn Error>> {
+    fn file_name(path: &TempPath) -> String {
+        Path::new(&**path)
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .to_string()
+    }
+
+    let field = synthetic_field(Point3dd([0.0, 0.0, 0.0]));
+    let basename = TempPath::new("check_chain_field");
+    field.save(&basename)?;
+
+    // Offsets on the first line, followed by the rows of the matrix.
+    let shift = TempPath::new("check_chain_shift.trm");
+    std::fs::write(&shift, "1 1 1\n1 0 0\n0 1 0\n0 0 1\n")?;
+    let scale = TempPath::new("check_chain_scale.trm");
+    std::fs::write(&scale, "0 0 0\n2 0 0\n0 2 0\n0 0 2\n")?;
+
+    // Paths are relative to the manifest, next to the other files.
+    let manifest = TempPath::new("check_chain.txt");
+    std::fs::write(
+        &manifest,
+        format!(
+            "# Shift, then deform\n\
+             affine  {}  # into the field\n\
+             gis     {}\n\
+             \n\
+             affine  {}  inverse\n",
+            file_name(&shift),
+            file_name(&basename),
+            file_name(&scale)
+        ),
+    )?;
+    let chain = TransformChain::load_file(&manifest)?;
+    assert_eq!(chain.len(), 3);
+
+    let mut state = 31;
+    let inside = BoundingBox::new(Point3dd([-1.0, -1.0, -1.0]), Point3dd([4.5, 3.5, 2.5]));
+    for _ in 0..100 {
+        let p = random_point(&mut state, &inside);
+        let mut expected = field.deformation(&Point3dd([p[0] + 1.0, p[1] + 1.0, p[2] + 1.0]));
+        for k in 0..3 {
+            expected.0[k] /= 2.0;
+        }
+        assert!(same_point(&chain.transform(&p), &expected, 1e-9));
+    }
+
+    // Points leaving the domain of the field become NaN, and stay so.
+    for p in &[[5.5, 0.0, 0.0], [0.0, -1.5, 0.0], [0.0, 0.0, 3.5]] {
+        assert!(chain.transform(&Point3dd(*p)).is_nan());
+    }
+
+    // Each line in error is reported with its number.
+    for (line, error) in &[
+        ("affine  {}  inverted", ":2: unknown option 'inverted'"),
+        ("affine", ":2: missing file name"),
+        ("spline  {}", ":2: unknown transform 'spline'"),
+    ] {
+        let line = line.replace("{}", &file_name(&shift));
+        std::fs::write(&manifest, format!("# Broken\n{}\n", line))?;
+        let message = TransformChain::load_file(&manifest)
+            .unwrap_err()
+            .to_string();
+        assert!(message.ends_with(error), "{}", message);
+    }
+
+    // The field cannot be inverted, and missing files are errors.
+    std::fs::write(&manifest, format!("gis {} inverse\n", file_name(&basename)))?;
+    assert!(TransformChain::load_file(&manifest).is_err());
+    std::fs::write(&manifest, "affine missing.trm\n")?;
+    assert!(TransformChain::load_file(&manifest).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn check_chain_nan() -> Result<(), Box<dyn Error>> {
+    fn shift() -> Box<dyn Transform> {
+        Box::new(AffineTransform::new(
+            [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+            [1.0, 1.0, 1.0],
+        ))
+    }
+
+    let mut chain = TransformChain::new();
+    chain.push(shift(), false)?;
+    chain.push(Box::new(holed_field(Point3dd([0.0, 0.0, 0.0]))), false)?;
+    chain.push(shift(), true)?;
+
+    // The control point (3, 4) of the field is NaN, whatever z.
+    let p = chain.transform(&Point3dd([0.5, 1.0, 0.0]));
+    assert!(p.0.iter().all(|v| v.is_nan()));
+
+    // Close to the hole, though not in a cell touching it.
+    let q = chain.transform(&Point3dd([2.5, 2.0, 1.0]));
+    assert!(!q.is_nan());
+
+    // NaN inputs never get a value, even without any domain.
+    let mut affine = TransformChain::new();
+    affine.push(shift(), false)?;
+    assert!(affine.transform(&Point3dd([f64::NAN, 0.0, 0.0])).is_nan());
+    assert!(chain
+        .transform(&Point3dd([f64::NAN, 0.0, 0.0]))
+        .0
+        .iter()
+        .all(|v| v.is_nan()));
+
+    Ok(())
+}
+
+#[test]
+fn check_resample_upsampling() {
+    let field = synthetic_field(Point3dd([-2.0, 1.0, 0.5]));
+    let fine = field.resample_spacing(Point3dd([0.25, 0.25, 0.25]));
+    let d = fine.grid().dimensions;
+
+    assert!(same_point(fine.origin(), field.origin(), 0.0));
+    assert_eq!(d, [24, 20, 16]);
+
+    // The control points of the field are kept as they are, the new ones
+    // being interpolated between them.
+    for z in 0..d[2] {
+        for y in 0..d[1] {
+            for x in 0..d[0] {
+                let v = fine.ctrl_point(x, y, z);
+                if x % 2 == 0 && y % 2 == 0 && z % 2 == 0 {
+                    assert_eq!(v.0, field.ctrl_point(x / 2, y / 2, z / 2).0);
+                }
+
+                let p = fine.grid().position(x, y, z);
+                let expected = field.displacement(&p);
+                for k in 0..3 {
+                    assert!((f64::from(v[k]) - expected[k]).abs() < 1e-6);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn check_resample_downsampling() -> Result<(), Box<dyn Error>> {
+    // Displacement along x alternating between 1 and 3 from one control
+    // point to the next, that is 2 plus the highest frequency of the grid.
+    let grid = Grid::new(
+        Point3dd([0.0, 0.0, 0.0]),
+        Point3dd([0.5, 0.5, 0.5]),
+        [12, 10, 8],
+    );
+    let d = grid.dimensions;
+    let mut values = Vec::with_capacity(grid.len() * 3);
+    for _ in 0..d[2] * d[1] {
+        for x in 0..d[0] {
+            values.extend_from_slice(&[2.0 + if x % 2 == 0 { 1.0 } else { -1.0 }, 0.0, 0.0]);
+        }
+    }
+    let field = GISTransform::from_grid(&grid, values)?;
+    let coarse = field.resample_spacing(Point3dd([1.0, 1.0, 1.0]));
+    let c = coarse.grid().dimensions;
+
+    assert_eq!(c, [6, 5, 4]);
+
+    // Sampled as-is, the coarse nodes would all fall on the peaks. Away from
+    // the borders, where the filter misses neighbours, only the mean is left.
+    for z in 1..c[2] - 1 {
+        for y in 1..c[1] - 1 {
+            for x in 1..c[0] - 1 {
+                let p = coarse.grid().position(x, y, z);
+                assert_eq!(field.displacement(&p)[0], 3.0);
+
+                let v = coarse.ctrl_point(x, y, z);
+                assert!((v[0] - 2.0).abs() < 1e-6);
+                assert_eq!((v[1], v[2]), (0.0, 0.0));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn check_pyramid_error() {
+    let field = synthetic_field(Point3dd([-2.0, 1.0, 0.5]));
+    let domain = field.domain().unwrap();
+    let pyramid = FieldPyramid::new(synthetic_field(Point3dd([-2.0, 1.0, 0.5])), 4);
+
+    assert_eq!(pyramid.len(), 3);
+    assert!(pyramid.max_error(1) > 0.0);
+
+    // The bound holds anywhere in the field, not only on control points.
+    let mut state = 37;
+    for l in 0..pyramid.len() {
+        assert_eq!(pyramid.nan_mismatch(l), 0);
+
+        let mut max = 0f64;
+        for _ in 0..2000 {
+            let p = random_point(&mut state, &domain);
+            let a = field.displacement(&p);
+            let b = pyramid.level(l).displacement(&p);
+            assert!(!a.is_nan() && !b.is_nan());
+
+            let distance = (0..3).map(|k| (a[k] - b[k]).powi(2)).sum::<f64>();
+            max = max.max(distance.sqrt());
+        }
+
+        assert!(max <= pyramid.max_error(l) + 1e-9);
+    }
+    assert_eq!(pyramid.select(f64::INFINITY, 0.0), pyramid.len() - 1);
+    assert_eq!(pyramid.select(0.0, 0.0), 0);
+
+    // Coarse cells spread the holes over positions the full field defines.
+    let field = holed_field(Point3dd([-2.0, 1.0, 0.5]));
+    let pyramid = FieldPyramid::new(holed_field(Point3dd([-2.0, 1.0, 0.5])), 4);
+    let mut lost = 0;
+    for _ in 0..2000 {
+        let p = random_point(&mut state, &domain);
+        let a = field.displacement(&p);
+        let b = pyramid.level(1).displacement(&p);
+        if !a.is_nan() && b.is_nan() {
+            lost += 1;
+        }
+
+        // Where both are defined, the bound still holds.
+        if !a.is_nan() && !b.is_nan() {
+            let distance = (0..3).map(|k| (a[k] - b[k]).powi(2)).sum::<f64>();
+            assert!(distance.sqrt() <= pyramid.max_error(1) + 1e-9);
+        }
+    }
+
+    assert!(lost > 0);
+    assert!(pyramid.nan_mismatch(1) > 0);
+    assert!(pyramid.nan_loss(1) > 0.0 && pyramid.nan_loss(1) < 0.5);
+
+    // Coarse levels are only used once the loss is accepted.
+    let last = pyramid.len() - 1;
+    assert_eq!(pyramid.select(f64::INFINITY, 0.0), 0);
+    assert!(pyramid.select(f64::INFINITY, pyramid.nan_loss(1)) >= 1);
+    assert_eq!(pyramid.select(f64::INFINITY, 1.0), last);
+    assert_eq!(pyramid.select(pyramid.max_error(1), 1.0), 1);
+
+    let p = Point3dd([1.0, 1.5, 1.5]);
+    assert!(!pyramid.level(last).deformation(&p).is_nan());
+    assert!(same_point(
+        &pyramid.deformation(&p, f64::INFINITY, 1.0),
+        &pyramid.level(last).deformation(&p),
+        0.0
+    ));
+}
+
+#[test]
+fn check_transform_box() {
+    let field = holed_field(Point3dd([0.0, 0.0, 0.0]));
+    let source = BoundingBox::new(Point3dd([0.2, 0.4, 0.3]), Point3dd([5.3, 4.1, 3.6]));
+    let n = 9;
+
+    // Same samples as the whole grid of the box, restricted to its faces.
+    let extent = source.extent();
+    let (mut samples, mut nan_samples) = (0, 0);
+    let mut bounds = BoundingBox::empty();
+    for z in 0..n {
+        for y in 0..n {
+            for x in 0..n {
+                let i = [x, y, z];
+                if (0..3).all(|k| i[k] > 0 && i[k] < n - 1) {
+                    continue;
+                }
+
+                let mut p = source.low.clone();
+                for k in 0..3 {
+                    p.0[k] = if i[k] == n - 1 {
+                        source.high[k]
+                    } else {
+                        source.low[k] + i[k] as f64 * (extent[k] / (n - 1) as f64)
+                    };
+                }
+
+                let q = field.deformation(&p);
+                samples += 1;
+                if q.is_nan() {
+                    nan_samples += 1;
+                } else {
+                    bounds.extend(&q);
+                }
+            }
+        }
+    }
+
+    let surface = field.transform_box(&source, n, Sampling::Surface);
+    assert_eq!(surface.samples, n * n * n - (n - 2) * (n - 2) * (n - 2));
+    assert_eq!(
+        (surface.samples, surface.nan_samples),
+        (samples, nan_samples)
+    );
+    assert!(nan_samples > 0);
+    assert!(same_point(&surface.bounds.low, &bounds.low, 0.0));
+    assert!(same_point(&surface.bounds.high, &bounds.high, 0.0));
+
+    let volume = field.transform_box(&source, n, Sampling::Volume);
+    assert_eq!(volume.samples, n * n * n);
+    assert!(volume.nan_samples >= surface.nan_samples);
+
+    // A flat box is all surface.
+    let flat = BoundingBox::new(Point3dd([0.2, 0.4, 1.0]), Point3dd([5.3, 4.1, 1.0]));
+    for sampling in &[Sampling::Surface, Sampling::Volume] {
+        assert_eq!(field.transform_box(&flat, n, *sampling).samples, n * n);
+    }
+}
+
+#[test]
+fn check_crop() -> Result<(), Box<dyn Error>> {
+    let field = synthetic_field(Point3dd([1.0, 2.0, 3.0]));
+    let region = BoundingBox::new(Point3dd([2.2, 3.1, 3.4]), Point3dd([4.1, 5.3, 5.0]));
+    let cropped = field.crop_mm(&region)?;
+
+    let basename = TempPath::new("check_crop");
+    cropped.save(&basename)?;
+    let cropped = GISTransform::load_file(&basename)?;
+
+    for i in 0..=10 {
+        let mut p = region.low.clone();
+        for k in 0..3 {
+            p.0[k] += (region.high[k] - region.low[k]) * f64::from(i) / 10.0;
+        }
+
+        assert!(same_point(
+            &field.deformation(&p),
+            &cropped.deformation(&p),
+            1e-9
+        ));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn check_transform_grid() -> Result<(), Box<dyn Error>> {
+    let field = synthetic_field(Point3dd([1.0, 2.0, 3.0]));
+    // Partially outside of the field, on purpose.
+    let grid = Grid::new(
+        Point3dd([0.3, 2.1, 2.9]),
+        Point3dd([0.35, 0.4, 0.45]),
+        [20, 14, 12],
+    );
+    let mut out = vec![Point3dd([0.0, 0.0, 0.0]); grid.len()];
+    field.transform_grid(&grid, &mut out)?;
+
+    let d = grid.dimensions;
+    for z in 0..d[2] {
+        for y in 0..d[1] {
+            for x in 0..d[0] {
+                let expected = field.deformation(&grid.position(x, y, z));
+                assert!(same_point(&expected, &out[grid.index(x, y, z)], 0.0));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn check_warp() -> Result<(), Box<dyn Error>> {
+    let grid = Grid::new(
+        Point3dd([0.0, 0.0, 0.0]),
+        Point3dd([1.0, 1.0, 2.0]),
+        [10, 8, 6],
+    );
+    let d = grid.dimensions;
+    let mut data = vec![];
+    for z in 0..d[2] {
+        for y in 0..d[1] {
+            for x in 0..d[0] {
+                let p = grid.position(x, y, z);
+                data.push(p[0] + 2.0 * p[1] + 3.0 * p[2]);
+            }
+        }
+    }
+    let volume = Volume::new(grid.clone(), VoxelType::Double, data)?;
+
+    // Target positions are pulled back by 1mm along x.
+    let shift = AffineTransform::new(
+        [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+        [1.0, 0.0, 0.0],
+    );
+    let target = Grid::new(
+        Point3dd([0.5, 0.5, 0.5]),
+        Point3dd([0.5, 0.5, 0.5]),
+        [20, 14, 20],
+    );
+
+    for interpolation in &[Interpolation::Trilinear, Interpolation::Cubic] {
+        let warped = warp::warp(&volume, &shift, &target, *interpolation, -1.0);
+        let t = target.dimensions;
+
+        for z in 0..t[2] {
+            for y in 0..t[1] {
+                for x in 0..t[0] {
+                    let p = target.position(x, y, z);
+                    let value = warped.get(x, y, z);
+
+                    // Borders are clamped for cubic interpolation, which is
+                    // then no longer exact for linear values.
+                    let border = p[0] + 1.0 < 1.0
+                        || p[0] + 1.0 > 8.0
+                        || p[1] < 1.0
+                        || p[1] > 6.0
+                        || p[2] < 2.0
+                        || p[2] > 8.0;
+
+                    if p[0] + 1.0 > 9.0 || p[1] > 7.0 || p[2] > 10.0 {
+                        assert_eq!(value, -1.0);
+                    } else if *interpolation == Interpolation::Trilinear || !border {
+                        let expected = p[0] + 1.0 + 2.0 * p[1] + 3.0 * p[2];
+                        assert!((value - expected).abs() < 1e-9);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn check_warp_labels() -> Result<(), Box<dyn Error>> {
+    // Two regions along x, labelled 1 on [0, 5) and 2 on [5, 8), 0 elsewhere.
+    let grid = Grid::new(
+        Point3dd([0.0, 0.0, 0.0]),
+        Point3dd([1.0, 1.0, 1.0]),
+        [8, 4, 4],
+    );
+    let d = grid.dimensions;
+    let mut data = vec![];
+    for _ in 0..d[2] {
+        for _ in 0..d[1] {
+            for x in 0..d[0] {
+                data.push(if x < 5 { 1.0 } else { 2.0 });
+            }
+        }
+    }
+    let labels = Volume::new(grid.clone(), VoxelType::S16, data)?;
+    let identity = AffineTransform::identity();
+
+    let float = Volume::filled(grid, VoxelType::Float, 1.0);
+    let target = Grid::new(
+        Point3dd([0.0, 0.0, 0.0]),
+        Point3dd([1.0, 1.0, 1.0]),
+        [1, 1, 1],
+    );
+    assert!(
+        warp::warp_labels(&float, &identity, &target, LabelInterpolation::Nearest, 0.0).is_err()
+    );
+
+    // Coarse voxels of 4mm, centered on 1.5 and 5.5: the second one covers
+    // 1mm of the first region, and 3mm of the second.
+    let target = Grid::new(
+        Point3dd([1.5, 1.5, 1.5]),
+        Point3dd([4.0, 1.0, 1.0]),
+        [2, 1, 1],
+    );
+    let warped = warp::warp_labels(
+        &labels,
+        &identity,
+        &target,
+        LabelInterpolation::Majority(4),
+        0.0,
+    )?;
+    assert_eq!(warped.data(), &[1.0, 2.0]);
+
+    let warped = warp::warp_labels(
+        &labels,
+        &identity,
+        &target,
+        LabelInterpolation::Nearest,
+        0.0,
+    )?;
+    assert_eq!(warped.data(), &[1.0, 2.0]);
+
+    // Voxels mostly outside of the source get the background.
+    let target = Grid::new(
+        Point3dd([9.0, 1.5, 1.5]),
+        Point3dd([4.0, 1.0, 1.0]),
+        [1, 1, 1],
+    );
+    let warped = warp::warp_labels(
+        &labels,
+        &identity,
+        &target,
+        LabelInterpolation::Majority(4),
+        0.0,
+    )?;
+    assert_eq!(warped.data(), &[0.0]);
+
+    Ok(())
+}
+
+#[test]
+fn check_nifti() -> Result<(), Box<dyn Error>> {
+    let field = synthetic_field(Point3dd([-2.0, 1.0, 0.5]));
+    let filename = TempPath::new("check_nifti.nii.gz");
+
+    nifti::save_gis(&filename, &field)?;
+    let loaded = nifti::load_file(&filename)?;
+
+    assert_eq!(field.dimensions()[..3], loaded.field().dimensions()[..3]);
+    for p in &[[-2.0, 1.0, 0.5], [0.3, 2.7, 1.1], [3.6, 5.4, 4.2]] {
+        let p = Point3dd(*p);
+        assert!(same_point(
+            &field.deformation(&p),
+            &loaded.deformation(&p),
+            1e-5
+        ));
+    }
+
+    // Written with a qform.
+    let to_world = rotated_to_world();
+    let oriented = OrientedField::new(field, to_world)?;
+    let filename = TempPath::new("check_nifti.nii");
+
+    nifti::save_file(&filename, &oriented)?;
+    let loaded = nifti::load_file(&filename)?;
+
+    for p in &[[8.0, 22.0, 32.0], [5.0, 21.0, 33.0]] {
+        let p = Point3dd(*p);
+        assert!(same_point(
+            &oriented.deformation(&p),
+            &loaded.deformation(&p),
+            1e-5
+        ));
+    }
+
+    // The jacobian map keeps the orientation of the field.
+    let map = oriented.jacobian_map();
+    nifti::save_oriented_volume(&filename, &map, oriented.to_world())?;
+    let (header, values) = nifti::read_file(&filename)?;
+
+    assert_eq!(header.dimensions, map.grid().dimensions);
+    let expected = oriented::voxel_to_world(map.grid(), oriented.to_world());
+    assert_eq!(header.to_world.matrix(), expected.matrix());
+    assert!(same_point(
+        &Point3dd(*header.to_world.offsets()),
+        &Point3dd(*expected.offsets()),
+        1e-6
+    ));
+    for (a, b) in map.data().iter().zip(&values) {
+        // Values are stored as 32 bits floats.
+        assert!((a - b).abs() < 1e-5);
+    }
+
+    Ok(())
+}
+
+// Displacements of a 2x2x2 field, component k of voxel i being i + 10 k,
+// along with the same field as written in files, volume by volume.
+fn small_field(to_world: AffineTransform) -> (OrientedField, Vec<f32>) {
+    let grid = Grid::new(
+        Point3dd([0.0, 0.0, 0.0]),
+        Point3dd([1.0, 1.0, 1.0]),
+        [2, 2, 2],
+    );
+    let interleaved = (0..8)
+        .flat_map(|i| (0..3).map(move |k| (i + 10 * k) as f32))
+        .collect::<Vec<_>>();
+    let volumes = (0..3)
+        .flat_map(|k| (0..8).map(move |i| (i + 10 * k) as f32))
+        .collect::<Vec<_>>();
+    let field = GISTransform::from_grid(&grid, interleaved).unwrap();
+
+    (OrientedField::new(field, to_world).unwrap(), volumes)
+}
+
+fn same_field(a: &OrientedField, b: &OrientedField) -> bool {
+    [[0.25, 0.5, 0.75], [1.5, 1.2, 0.3], [0.1, 1.0, 1.9]]
+        .iter()
+        .map(|v| a.to_world().transform(&Point3dd(*v)))
+        .all(|p| same_point(&a.deformation(&p), &b.deformation(&p), 1e-4))
+}
+
+#[test]
+fn check_nifti_headers() -> Result<(), Box<dyn Error>> {
+    let (field, volumes) = small_field(rotated_to_world());
+
+    // Big endian NIfTI-1, with only a qform: rotation of 90 degrees around z.
+    let mut bytes = vec![0u8; 352];
+    BigEndian::write_i32(&mut bytes[0..], 348);
+    for (i, d) in [4, 2, 2, 2, 3].iter().enumerate() {
+        BigEndian::write_i16(&mut bytes[40 + 2 * i..], *d);
+    }
+    BigEndian::write_i16(&mut bytes[68..], nifti::INTENT_DISPLACEMENT as i16);
+    BigEndian::write_i16(&mut bytes[70..], 16);
+    BigEndian::write_i16(&mut bytes[72..], 32);
+    for (i, v) in [1.0, 2.0, 2.0, 2.0].iter().enumerate() {
+        BigEndian::write_f32(&mut bytes[76 + 4 * i..], *v);
+    }
+    BigEndian::write_f32(&mut bytes[108..], 352.0);
+    BigEndian::write_i16(&mut bytes[252..], 1);
+    let quaternion = [0.0, 0.0, 0.5f32.sqrt(), 10.0, 20.0, 30.0];
+    for (i, v) in quaternion.iter().enumerate() {
+        BigEndian::write_f32(&mut bytes[256 + 4 * i..], *v);
+    }
+    bytes[344..348].copy_from_slice(b"n+1\0");
+    for v in &volumes {
+        bytes.extend_from_slice(&v.to_be_bytes());
+    }
+
+    let filename = TempPath::new("check_nifti_headers.nii");
+    std::fs::write(&filename, &bytes)?;
+    let loaded = nifti::load_file(&filename)?;
+    assert!(same_field(&field, &loaded));
+
+    // Missing values are reported, rather than read as zeros.
+    std::fs::write(&filename, &bytes[..bytes.len() - 4])?;
+    assert!(nifti::load_file(&filename).is_err());
+
+    // Little endian NIfTI-2 with an sform, 5D, in doubles scaled by 2 + 1.
+    let (field, volumes) = small_field(AffineTransform::new(
+        [[0.0, 0.0, 1.5], [0.0, -1.0, 0.0], [3.0, 0.0, 0.0]],
+        [-4.0, 5.0, 6.0],
+    ));
+    let mut bytes = vec![0u8; 544];
+    LittleEndian::write_i32(&mut bytes[0..], 540);
+    bytes[4..12].copy_from_slice(b"n+2\0\r\n\x1a\n");
+    LittleEndian::write_i16(&mut bytes[12..], 64);
+    LittleEndian::write_i16(&mut bytes[14..], 64);
+    for (i, d) in [5, 2, 2, 2, 1, 3].iter().enumerate() {
+        LittleEndian::write_i64(&mut bytes[16 + 8 * i..], *d);
+    }
+    for (i, v) in [1.0, 3.0, 1.0, 1.5].iter().enumerate() {
+        LittleEndian::write_f64(&mut bytes[104 + 8 * i..], *v);
+    }
+    LittleEndian::write_i64(&mut bytes[168..], 544);
+    LittleEndian::write_f64(&mut bytes[176..], 2.0);
+    LittleEndian::write_f64(&mut bytes[184..], 1.0);
+    LittleEndian::write_i32(&mut bytes[348..], 1);
+    let m = field.to_world().matrix();
+    let t = field.to_world().offsets();
+    for r in 0..3 {
+        for c in 0..3 {
+            LittleEndian::write_f64(&mut bytes[400 + 32 * r + 8 * c..], m[r][c]);
+        }
+        LittleEndian::write_f64(&mut bytes[400 + 32 * r + 24..], t[r]);
+    }
+    LittleEndian::write_i32(&mut bytes[504..], nifti::INTENT_VECTOR);
+    for v in &volumes {
+        bytes.extend_from_slice(&((f64::from(*v) - 1.0) / 2.0).to_le_bytes());
+    }
+
+    let filename = TempPath::new("check_nifti_headers.nii");
+    std::fs::write(&filename, &bytes)?;
+    let loaded = nifti::load_file(&filename)?;
+    assert!(same_field(&field, &loaded));
+
+    Ok(())
+}
+
+#[test]
+fn check_conventions() -> Result<(), Box<dyn Error>> {
+    // Constant displacement of (1, 2, 3) in LPS, as written by ANTs.
+    let grid = Grid::new(
+        Point3dd([0.0, 0.0, 0.0]),
+        Point3dd([1.0, 1.0, 1.0]),
+        [4, 4, 4],
+    );
+    let values = (0..grid.len())
+        .flat_map(|_| vec![1.0, 2.0, 3.0])
+        .collect::<Vec<f32>>();
+    let field = GISTransform::from_grid(&grid, values)?;
+    let filename = TempPath::new("check_conventions.nii");
+
+    nifti::save_gis(&filename, &field)?;
+    let ras = nifti::load_file_with(&filename, Convention::Lps, Convention::Ras)?;
+    let p = Point3dd([1.0, 1.0, 1.0]);
+    assert!(same_point(
+        &ras.deformation(&p),
+        &Point3dd([0.0, -1.0, 4.0]),
+        1e-9
+    ));
+
+    // The same point in LPS gets the original displacement.
+    let lps = Converted::new(Box::new(ras), Convention::Ras, Convention::Lps);
+    let q = Convention::Ras.convert(Convention::Lps, &p);
+    assert!(same_point(
+        &lps.transform(&q),
+        &Point3dd([0.0, 1.0, 4.0]),
+        1e-9
+    ));
+    assert!(same_point(
+        &Convention::Lps.convert(Convention::Ras, &lps.transform(&q)),
+        &Point3dd([0.0, -1.0, 4.0]),
+        1e-9
+    ));
+
+    // Used from RAS, a field of AIMS moves points along flipped axes.
+    let aims = Converted::new(Box::new(field), Convention::Lpi, Convention::Ras);
+    assert!(same_point(
+        &aims.transform(&Point3dd([-1.0, -1.0, -1.0])),
+        &Point3dd([-2.0, -3.0, -4.0]),
+        1e-9
+    ));
+
+    // Going through another convention and back is the identity.
+    let affine = AffineTransform::new(
+        [[1.0, 0.2, 0.0], [0.0, 1.0, 0.1], [0.3, 0.0, 1.0]],
+        [1.0, -2.0, 3.0],
+    );
+    let expected = affine.transform(&p);
+    let twice = Converted::new(
+        Box::new(Converted::new(
+            Box::new(affine),
+            Convention::Ras,
+            Convention::Lps,
+        )),
+        Convention::Lps,
+        Convention::Ras,
+    );
+    assert!(same_point(&twice.transform(&p), &expected, 1e-12));
+    assert!(same_point(
+        &twice.inverse().unwrap().transform(&expected),
+        &p,
+        1e-12
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn check_absolute() -> Result<(), Box<dyn Error>> {
+    let field = synthetic_field(Point3dd([-2.0, 1.0, 0.5]));
+    let absolute = field.to_absolute();
+    let back = absolute.to_displacement();
+
+    assert_eq!(absolute.mode(), FieldMode::Absolute);
+    assert_eq!(back.mode(), FieldMode::Displacement);
+
+    // Both representations differ past the last control points, where the
+    // values are kept constant.
+    for p in &[
+        [-2.0, 1.0, 0.5],
+        [0.3, 2.7, 1.1],
+        [3.4, 5.4, 3.9],
+        [9.0, 9.0, 9.0],
+    ] {
+        let p = Point3dd(*p);
+        let expected = field.deformation(&p);
+        assert!(same_point(&absolute.deformation(&p), &expected, 1e-5));
+        assert!(same_point(&back.deformation(&p), &expected, 1e-5));
+        assert!(same_point(
+            &absolute.displacement(&p),
+            &field.displacement(&p),
+            1e-5
+        ));
+    }
+
+    let grid = Grid::new(
+        Point3dd([-1.7, 1.2, 0.6]),
+        Point3dd([0.3, 0.4, 0.45]),
+        [14, 9, 7],
+    );
+    let mut expected = vec![Point3dd([0.0; 3]); grid.len()];
+    let mut out = vec![Point3dd([0.0; 3]); grid.len()];
+    field.transform_grid(&grid, &mut expected)?;
+    absolute.transform_grid(&grid, &mut out)?;
+    for (a, b) in expected.iter().zip(&out) {
+        assert!(same_point(a, b, 1e-5));
+    }
+
+    // Absolute fields are saved as displacements in GIS files, and with
+    // the vector intent in NIfTI files.
+    let basename = TempPath::new("check_absolute");
+    absolute.save(&basename)?;
+    let loaded = GISTransform::load_file(&basename)?;
+    let p = Point3dd([0.3, 2.7, 1.1]);
+    assert!(same_point(
+        &loaded.deformation(&p),
+        &field.deformation(&p),
+        1e-5
+    ));
+
+    let filename = format!("{}.nii", &*basename);
+    nifti::save_gis(&filename, &absolute)?;
+    let loaded = nifti::load_file(&filename)?.with_mode(FieldMode::Absolute);
+    assert!(same_point(
+        &loaded.deformation(&p),
+        &field.deformation(&p),
+        1e-5
+    ));
+    assert!(same_point(
+        &loaded.to_displacement().deformation(&p),
+        &field.deformation(&p),
+        1e-5
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn check_velocity() -> Result<(), Box<dyn Error>> {
+    // The exponential of a constant velocity is the same translation.
+    let grid = Grid::new(
+        Point3dd([0.0, 0.0, 0.0]),
+        Point3dd([1.0, 1.0, 1.0]),
+        [6, 6, 6],
+    );
+    let values = (0..grid.len())
+        .flat_map(|_| vec![2.5, -1.0, 0.5])
+        .collect::<Vec<f32>>();
+    let velocity = GISTransform::from_grid(&grid, values)?;
+    let p = Point3dd([1.5, 2.5, 3.5]);
+    assert!(same_point(
+        &velocity.exponential()?.deformation(&p),
+        &Point3dd([4.0, 1.5, 4.0]),
+        1e-5
+    ));
+    assert!(same_point(
+        &velocity.inverse_exponential()?.deformation(&p),
+        &Point3dd([-1.0, 3.5, 3.0]),
+        1e-5
+    ));
+
+    // Both exponentials undo each other, away from the borders.
+    let velocity = synthetic_field(Point3dd([0.0, 0.0, 0.0]));
+    let forward = velocity.exponential()?;
+    let backward = velocity.inverse_exponential()?;
+    for p in &[[1.5, 1.5, 1.5], [2.0, 2.5, 1.8], [3.0, 3.2, 2.0]] {
+        let p = Point3dd(*p);
+        assert!(same_point(
+            &backward.deformation(&forward.deformation(&p)),
+            &p,
+            0.02
+        ));
+    }
+
+    // Absolute positions are no velocities.
+    assert!(velocity.to_absolute().exponential().is_err());
+    assert!(
+        OrientedField::new(velocity.to_absolute(), rotated_to_world())?
+            .inverse_exponential()
+            .is_err()
+    );
+
+    // Velocities too large to be squared in a bounded number of steps.
+    for v in &[f32::INFINITY, 1e30] {
+        let mut values = vec![0.0; grid.len() * 3];
+        values[40] = *v;
+        let velocity = GISTransform::from_grid(&grid, values)?;
+        assert!(velocity.exponential().is_err());
+        assert!(velocity.inverse_exponential().is_err());
+    }
+
+    // DARTEL velocities are in voxels: one voxel along i is 2mm along y.
+    let values = (0..grid.len())
+        .flat_map(|_| vec![1.0, 0.0, 0.0])
+        .collect::<Vec<f32>>();
+    let velocity = OrientedField::new(GISTransform::from_grid(&grid, values)?, rotated_to_world())?;
+    let p = rotated_to_world().transform(&Point3dd([2.5, 2.5, 2.5]));
+    assert!(same_point(
+        &velocity.from_voxel_units().exponential()?.deformation(&p),
+        &Point3dd([p[0], p[1] + 2.0, p[2]]),
+        1e-5
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn check_fnirt() -> Result<(), Box<dyn Error>> {
+    // B-splines sum to 1, so constant coefficients give the same constant
+    // displacement, along FSL axes: x is flipped as the determinant of the
+    // orientation is positive.
+    let to_world = AffineTransform::new(
+        [[2.0, 0.0, 0.0], [0.0, 2.0, 0.0], [0.0, 0.0, 2.0]],
+        [0.0, 0.0, 0.0],
+    );
+    let field = SplineField::new([6, 6, 6], [2.0; 3], vec![[1.0, 2.0, 3.0]; 216], to_world)?;
+
+    assert!(same_point(
+        &field.deformation(&Point3dd([4.0, 4.0, 4.0])),
+        &Point3dd([3.0, 6.0, 7.0]),
+        1e-12
+    ));
+    assert!(same_point(
+        &field.deformation(&Point3dd([11.9, 0.0, 5.0])),
+        &Point3dd([10.9, 2.0, 8.0]),
+        1e-12
+    ));
+    assert!(field.deformation(&Point3dd([12.0, 4.0, 4.0])).is_nan());
+    assert!(field.deformation(&Point3dd([-0.1, 4.0, 4.0])).is_nan());
+
+    Ok(())
+}
+
+#[test]
+fn check_metaimage() -> Result<(), Box<dyn Error>> {
+    let field = synthetic_field(Point3dd([-2.0, 1.0, 0.5]));
+    let points = [[-2.0, 1.0, 0.5], [0.3, 2.7, 1.1], [3.6, 5.4, 4.2]];
+
+    for extension in &["mha", "mhd"] {
+        let filename = TempPath::new(&format!("check_metaimage.{}", extension));
+
+        metaimage::save_gis(&filename, &field)?;
+        let loaded = metaimage::load_file(&filename)?;
+
+        assert_eq!(field.dimensions()[..3], loaded.field().dimensions()[..3]);
+        for p in &points {
+            let p = Point3dd(*p);
+            assert!(same_point(
+                &field.deformation(&p),
+                &loaded.deformation(&p),
+                1e-5
+            ));
+        }
+    }
+
+    // Oriented fields keep their orientation, absolute ones are converted.
+    let to_world = rotated_to_world();
+    let oriented = OrientedField::new(field, to_world)?.to_absolute();
+    let filename = TempPath::new("check_metaimage_oriented.mha");
+
+    metaimage::save_file(&filename, &oriented)?;
+    let loaded = metaimage::load_file(&filename)?;
+
+    assert_eq!(loaded.field().mode(), FieldMode::Displacement);
+    for p in &[[8.0, 22.0, 32.0], [5.0, 21.0, 33.0]] {
+        let p = Point3dd(*p);
+        assert!(same_point(
+            &oriented.deformation(&p),
+            &loaded.deformation(&p),
+            1e-4
+        ));
+    }
+
+    // Fields are RAS in memory, as for NIfTI, and LPS in the file.
+    let lps = metaimage::load_file_with(&filename, Convention::Lps)?;
+    for p in &[[8.0, 22.0, 32.0], [5.0, 21.0, 33.0]] {
+        let p = Point3dd(*p);
+        assert!(same_point(
+            &Convention::Ras.convert(Convention::Lps, &oriented.deformation(&p)),
+            &lps.deformation(&Convention::Ras.convert(Convention::Lps, &p)),
+            1e-4
+        ));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn check_reader_conventions() -> Result<(), Box<dyn Error>> {
+    let field = OrientedField::new(
+        synthetic_field(Point3dd([-2.0, 1.0, 0.5])),
+        rotated_to_world(),
+    )?;
+
+    // ITK writes MetaImage fields in LPS, ANTs writes NIfTI ones with a RAS
+    // header and LPS displacements.
+    let mha = TempPath::new("check_reader_conventions.mha");
+    metaimage::save_file(&mha, &field)?;
+
+    let gis = field.field();
+    let grid = gis.grid();
+    let mut values = Vec::with_capacity(grid.len() * 3);
+    for z in 0..grid.dimensions[2] {
+        for y in 0..grid.dimensions[1] {
+            for x in 0..grid.dimensions[0] {
+                let v = gis.ctrl_point(x, y, z);
+                values.extend_from_slice(&[-v[0], -v[1], v[2]]);
+            }
+        }
+    }
+    let ants = OrientedField::new(GISTransform::from_grid(&grid, values)?, rotated_to_world())?;
+    let nii = TempPath::new("check_reader_conventions.nii");
+    nifti::save_file(&nii, &ants)?;
+
+    // Whatever the format, the last argument is the convention of the result.
+    for convention in &[Convention::Ras, Convention::Lps] {
+        let expected = field.change_convention(Convention::Ras, *convention);
+        let from_nifti = nifti::load_file_with(&nii, Convention::Lps, *convention)?;
+        let from_metaimage = metaimage::load_file_with(&mha, *convention)?;
+
+        for p in &[[-1.0, 2.0, 1.5], [1.3, 3.2, 2.1], [2.9, 4.4, 3.3]] {
+            let p = expected.to_world().transform(&Point3dd(*p));
+            let q = expected.deformation(&p);
+            assert!(same_point(&from_nifti.deformation(&p), &q, 1e-4));
+            assert!(same_point(&from_metaimage.deformation(&p), &q, 1e-4));
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn check_nrrd() -> Result<(), Box<dyn Error>> {
+    let to_world = rotated_to_world();
+    let field = OrientedField::new(synthetic_field(Point3dd([-2.0, 1.0, 0.5])), to_world)?;
+
+    for (name, encoding) in &[("raw", Encoding::Raw), ("gzip", Encoding::Gzip)] {
+        let filename = TempPath::new(&format!("check_nrrd_{}.nrrd", name));
+
+        nrrd::save_file(&filename, &field, *encoding)?;
+        let loaded = nrrd::load_file(&filename)?;
+
+        for p in &[[8.0, 22.0, 32.0], [5.0, 21.0, 33.0]] {
+            let p = Point3dd(*p);
+            assert!(same_point(
+                &field.deformation(&p),
+                &loaded.deformation(&p),
+                1e-4
+            ));
+        }
+    }
+
+    // Slicer fields in LPS are moved to RAS, along with their displacements.
+    let filename = TempPath::new("check_nrrd_lps.nrrd");
+    let mut bytes = b"NRRD0004\ntype: float\ndimension: 4\nspace: left-posterior-superior\n\
+        sizes: 3 2 2 2\nspace directions: none (2,0,0) (0,2,0) (0,0,2)\n\
+        kinds: vector domain domain domain\nendian: little\nencoding: raw\n\
+        space origin: (1,2,3)\n\n"
+        .to_vec();
+    for _ in 0..8 {
+        for v in &[1f32, 2.0, 3.0] {
+            bytes.extend_from_slice(&v.to_le_bytes());
+        }
+    }
+    std::fs::write(&filename, bytes)?;
+
+    let loaded = nrrd::load_file(&filename)?;
+    assert!(same_point(
+        &loaded.deformation(&Point3dd([-2.0, -3.0, 4.0])),
+        &Point3dd([-3.0, -5.0, 7.0]),
+        1e-6
+    ));
+
+    // Saved in LPS, the field is not mirrored when loaded back.
+    let lps = nrrd::load_file_with(&filename, Convention::Lps)?;
+    assert!(same_point(
+        &lps.deformation(&Point3dd([2.0, 3.0, 4.0])),
+        &Point3dd([3.0, 5.0, 7.0]),
+        1e-6
+    ));
+    nrrd::save_file_with(&filename, &lps, Convention::Lps, Encoding::Raw)?;
+    let loaded = nrrd::load_file(&filename)?;
+    assert!(same_point(
+        &loaded.deformation(&Point3dd([-2.0, -3.0, 4.0])),
+        &Point3dd([-3.0, -5.0, 7.0]),
+        1e-6
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn check_itk() -> Result<(), Box<dyn Error>> {
+    // Rotation of 90 degrees around z centered on (1, 0, 0), followed by
+    // a scaling of 2 around (0, 0, 1). The last transform is applied first.
+    let filename = TempPath::new("check_itk.tfm");
+    std::fs::write(
+        &filename,
+        "#Insight Transform File V1.0\n\
+         #Transform 0\n\
+         Transform: CompositeTransform_double_3_3\n\
+         #Transform 1\n\
+         Transform: AffineTransform_double_3_3\n\
+         Parameters: 2 0 0 0 2 0 0 0 2 0 0 0\n\
+         FixedParameters: 0 0 1\n\
+         #Transform 2\n\
+         Transform: Euler3DTransform_double_3_3\n\
+         Parameters: 0 0 1.5707963267948966 0 0 3\n\
+         FixedParameters: 1 0 0 0\n",
+    )?;
+
+    let chain = itk::load_file(&filename)?;
+    let affine = itk::load_affine(&filename, Convention::Lps)?;
+    assert_eq!(chain.len(), 2);
+
+    // (2, 0, 0) rotates to (1, 1, 3), then scales to (2, 2, 5).
+    let p = Point3dd([2.0, 0.0, 0.0]);
+    assert!(same_point(
+        &chain.transform(&p),
+        &Point3dd([2.0, 2.0, 5.0]),
+        1e-9
+    ));
+    assert!(same_point(
+        &affine.transform(&p),
+        &Point3dd([2.0, 2.0, 5.0]),
+        1e-9
+    ));
+
+    // The same transform, applied to RAS coordinates.
+    let ras = itk::load_file_with(&filename, Convention::Ras)?;
+    let q = Point3dd([-2.0, 0.0, 0.0]);
+    assert!(same_point(
+        &ras.transform(&q),
+        &Point3dd([-2.0, -2.0, 5.0]),
+        1e-9
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn check_flirt() -> Result<(), Box<dyn Error>> {
+    // The x axis of the neurological source is flipped in FSL coordinates,
+    // but not the one of the radiological reference.
+    let source = Geometry::new(
+        [10, 10, 10],
+        AffineTransform::new(
+            [[2.0, 0.0, 0.0], [0.0, 2.0, 0.0], [0.0, 0.0, 2.0]],
+            [-10.0, -10.0, -10.0],
+        ),
+    );
+    let reference = Geometry::new(
+        [20, 20, 20],
+        AffineTransform::new(
+            [[-1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+            [5.0, 0.0, 0.0],
+        ),
+    );
+
+    let transform = flirt::to_world(&AffineTransform::identity(), &source, &reference)?;
+    assert!(same_point(
+        &transform.transform(&Point3dd([0.0, 0.0, 0.0])),
+        &Point3dd([-3.0, 10.0, 10.0]),
+        1e-9
+    ));
+
+    let filename = TempPath::new("check_flirt.mat");
+    flirt::save_file(&filename, &transform, &source, &reference)?;
+    let loaded = flirt::load_file(&filename, &source, &reference)?;
+    for p in &[[0.0, 0.0, 0.0], [1.0, -2.0, 3.0]] {
+        let p = Point3dd(*p);
+        assert!(same_point(
+            &transform.transform(&p),
+            &loaded.transform(&p),
+            1e-9
+        ));
+    }
+
+    // Only the header of the image is read, its values may be missing.
+    let image = TempPath::new("check_flirt.nii");
+    nifti::save_file(
+        &image,
+        &OrientedField::new(synthetic_field(Point3dd([0.0; 3])), rotated_to_world())?,
+    )?;
+    let bytes = std::fs::read(&image)?;
+    std::fs::write(&image, &bytes[..352])?;
+    assert!(nifti::load_file(&image).is_err());
+
+    let geometry = Geometry::load_file(&image)?;
+    assert_eq!(geometry.dimensions(), &[12, 10, 8]);
+    let expected = oriented::voxel_to_world(
+        &synthetic_field(Point3dd([0.0; 3])).grid(),
+        &rotated_to_world(),
+    );
+    assert_eq!(geometry.to_world().matrix(), expected.matrix());
+    assert_eq!(geometry.to_world().offsets(), expected.offsets());
+
+    Ok(())
+}
+
+#[test]
+fn check_absolute_bounds() {
+    // Absolute fields keep the last position in the last cell of each axis,
+    // so their displacement there is not bounded by the control points.
+    let field = synthetic_field(Point3dd([0.0, 0.0, 0.0]));
+    let region = BoundingBox::new(Point3dd([5.7, 1.0, 1.0]), Point3dd([5.9, 1.2, 1.2]));
+
+    for field in &[field.to_displacement(), field.to_absolute()] {
+        let pyramid = DisplacementPyramid::new(field, 2);
+        let enclosing = pyramid.enclosing_box(&region);
+
+        for p in &[[5.8, 1.1, 1.1], [5.9, 1.2, 1.2], [5.7, 1.0, 1.2]] {
+            let q = field.deformation(&Point3dd(*p));
+            assert!(enclosing.contains(&q), "{:?} not in {:?}", q, enclosing);
+        }
+
+        // The source point has to be found back from its deformation.
+        let p = Point3dd([5.8, 1.1, 1.1]);
+        let q = field.deformation(&p);
+        let target = BoundingBox::new(
+            Point3dd([q[0] - 0.01, q[1] - 0.01, q[2] - 0.01]),
+            Point3dd([q[0] + 0.01, q[1] + 0.01, q[2] + 0.01]),
+        );
+        let voxels = pyramid.preimage_voxels(field, &target);
+        assert!(voxels.iter().any(|v| v.region.contains(&p)));
+    }
+}
+
+#[test]
+fn check_jacobian() -> Result<(), Box<dyn Error>> {
+    // One-sided differences on the borders leave no NaN on the grid.
+    let field = synthetic_field(Point3dd([-2.0, 1.0, 0.5]));
+    let map = field.jacobian_map();
+    assert!(map.data().iter().all(|v| !v.is_nan()));
+
+    // J = I + Du A^-1 for oriented fields, Du being taken in the field frame.
+    let to_world = rotated_to_world();
+    let inverse = to_world.inverted().unwrap();
+    let oriented = OrientedField::new(field, to_world)?;
+
+    for q in &[[-2.0, 1.0, 0.5], [0.3, 2.7, 1.1], [3.6, 5.4, 4.2]] {
+        let q = Point3dd(*q);
+        let mut du = oriented.field().jacobian(&q);
+        for (k, row) in du.iter_mut().enumerate() {
+            row[k] -= 1.0;
+        }
+
+        let world = oriented.jacobian(&oriented.to_world().transform(&q));
+        let m = inverse.matrix();
+        for r in 0..3 {
+            for c in 0..3 {
+                let expected = if r == c { 1.0 } else { 0.0 }
+                    + (0..3).map(|k| du[r][k] * m[k][c]).sum::<f64>();
+                assert!((world[r][c] - expected).abs() < 1e-9);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn check_enclosing_box() {
+    // Regions of random size and position, including the last cells and
+    // the ones around NaN control points, some partly out of the field.
+    let field = holed_field(Point3dd([-2.0, 1.0, 0.5]));
+    let domain = field.domain().unwrap();
+    let area = BoundingBox::new(
+        Point3dd([
+            domain.low[0] - 1.0,
+            domain.low[1] - 1.0,
+            domain.low[2] - 1.0,
+        ]),
+        Point3dd([
+            domain.high[0] + 1.0,
+            domain.high[1] + 1.0,
+            domain.high[2] + 1.0,
+        ]),
+    );
+    let mut state = 27;
+
+    for field in &[field.to_displacement(), field.to_absolute()] {
+        let pyramid = DisplacementPyramid::new(field, 2);
+
+        for _ in 0..200 {
+            let low = random_point(&mut state, &area);
+            let mut high = low.clone();
+            for k in 0..3 {
+                high.0[k] += 2.0 * random(&mut state);
+            }
+            let region = BoundingBox::new(low, high);
+            let enclosing = pyramid.enclosing_box(&region);
+
+            for _ in 0..50 {
+                let q = field.deformation(&random_point(&mut state, &region));
+                assert!(
+                    q.is_nan() || enclosing.contains(&q),
+                    "{:?} not in {:?}",
+                    q,
+                    enclosing
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn check_preimage() {
+    // Every source point deformed into the target has to be found back,
+    // both in the returned blocks and in the returned voxels.
+    let field = holed_field(Point3dd([-2.0, 1.0, 0.5]));
+    let domain = field.domain().unwrap();
+    let mut state = 28;
+    let mut found = 0;
+
+    for field in &[field.to_displacement(), field.to_absolute()] {
+        let pyramid = DisplacementPyramid::new(field, 2);
+
+        for _ in 0..20 {
+            let low = random_point(&mut state, &domain);
+            let mut high = low.clone();
+            for k in 0..3 {
+                high.0[k] += 1.5 * random(&mut state);
+            }
+            let target = BoundingBox::new(low, high);
+
+            let blocks = pyramid.preimage_blocks(&target);
+            let voxels = pyramid.preimage_voxels(field, &target);
+
+            for _ in 0..2000 {
+                let p = random_point(&mut state, &domain);
+                let q = field.deformation(&p);
+                if q.is_nan() || !target.contains(&q) {
+                    continue;
+                }
+
+                found += 1;
+                assert!(blocks.iter().any(|b| b.region.contains(&p)), "{:?}", p);
+                assert!(voxels.iter().any(|v| v.region.contains(&p)), "{:?}", p);
+            }
+        }
+    }
+    assert!(found > 0);
+}
+
+#[test]
+fn check_transform_sphere() -> Result<(), Box<dyn Error>> {
+    // Linear displacement u(p) = A p, reproduced exactly by trilinear
+    // interpolation, so that the jacobian is I + A everywhere.
+    let a = [[0.1, 0.05, 0.0], [0.0, -0.2, 0.0], [0.0, 0.0, 0.05]];
+    let grid = Grid::new(
+        Point3dd([0.0, 0.0, 0.0]),
+        Point3dd([0.5, 0.5, 0.5]),
+        [12, 10, 8],
+    );
+    let d = grid.dimensions;
+    let mut values = Vec::with_capacity(grid.len() * 3);
+    for z in 0..d[2] {
+        for y in 0..d[1] {
+            for x in 0..d[0] {
+                let p = grid.position(x, y, z);
+                for row in &a {
+                    values.push((0..3).map(|k| row[k] * p[k]).sum::<f64>() as f32);
+                }
+            }
+        }
+    }
+    let field = GISTransform::from_grid(&grid, values)?;
+    let pyramid = DisplacementPyramid::new(&field, 2);
+
+    let source = Sphere {
+        center: Point3dd([3.0, 2.5, 2.0]),
+        radius: 0.8,
+    };
+    let sphere = field.transform_sphere(&source, &pyramid)?;
+    assert!(!sphere.nan);
+
+    // Each semi-axis is the image of a radius of the source sphere.
+    let mut j = a;
+    for (k, row) in j.iter_mut().enumerate() {
+        row[k] += 1.0;
+    }
+    let inverse = AffineTransform::new(j, [0.0; 3]).inverted().unwrap();
+    for (axis, radius) in sphere.ellipsoid.axes.iter().zip(&sphere.ellipsoid.radii) {
+        let v = Point3dd([axis[0] * radius, axis[1] * radius, axis[2] * radius]);
+        let u = inverse.transform(&v);
+        let norm = (0..3).map(|k| u[k] * u[k]).sum::<f64>().sqrt();
+        assert!((norm - source.radius).abs() < 1e-5);
+    }
+
+    // The deformed sphere is in both the enclosing sphere and box.
+    let mut state = 29;
+    for _ in 0..500 {
+        let mut v = [0.0; 3];
+        for c in v.iter_mut() {
+            *c = random(&mut state) - 0.5;
+        }
+        let norm = (0..3).map(|k| v[k] * v[k]).sum::<f64>().sqrt();
+        let mut p = source.center.clone();
+        for (k, c) in v.iter().enumerate() {
+            p.0[k] += c / norm * source.radius;
+        }
+
+        let q = field.deformation(&p);
+        let e = &sphere.enclosing_sphere;
+        let distance = (0..3)
+            .map(|k| (q[k] - e.center[k]).powi(2))
+            .sum::<f64>()
+            .sqrt();
+        assert!(distance <= e.radius);
+        assert!(sphere.enclosing_box.contains(&q));
+    }
+
+    // Pyramids of fields on another grid, or in another mode, are refused.
+    let other = DisplacementPyramid::new(&synthetic_field(Point3dd([1.0, 0.0, 0.0])), 2);
+    assert!(field.transform_sphere(&source, &other).is_err());
+    let other = DisplacementPyramid::new(&field.to_absolute(), 2);
+    assert!(field.transform_sphere(&source, &other).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn check_registry() -> Result<(), Box<dyn Error>> {
+    fn translation(x: f64, y: f64, z: f64) -> Box<dyn Transform> {
+        let identity = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+        Box::new(AffineTransform::new(identity, [x, y, z]))
+    }
+
+    let mut registry = SpaceRegistry::new();
+    registry.insert("A", "X", translation(1.0, 0.0, 0.0));
+    registry.insert("A", "B", translation(0.0, 2.0, 0.0));
+    registry.insert("B", "C", translation(0.0, 0.0, 3.0));
+    registry.insert("C", "X", translation(10.0, 0.0, 0.0));
+
+    assert_eq!(registry.spaces(), vec!["A", "B", "C", "X"]);
+    assert_eq!(
+        registry.registered(),
+        vec![("A", "X"), ("A", "B"), ("B", "C"), ("C", "X")]
+    );
+
+    // Both A -> X -> C and A -> B -> C are two transforms long, the first
+    // one found going through the inverse of C -> X.
+    assert_eq!(
+        registry.path("A", "C"),
+        Some(vec!["A".to_string(), "B".to_string(), "C".to_string()])
+    );
+    assert_eq!(registry.path("A", "A"), Some(vec!["A".to_string()]));
+
+    // Only inverses lead back to A, C -> X -> A using the fewest.
+    assert_eq!(
+        registry.path("C", "A"),
+        Some(vec!["C".to_string(), "X".to_string(), "A".to_string()])
+    );
+    let points = [Point3dd([1.0, 2.0, 3.0]), Point3dd([-4.0, 5.5, 0.0])];
+    let mapped = registry.transform_points("C", "A", &points)?;
+    for (p, q) in points.iter().zip(mapped.iter()) {
+        assert!(same_point(&Point3dd([p[0] + 9.0, p[1], p[2]]), q, 1e-9));
+    }
+
+    // The GIS field has no inverse, its target being a dead end.
+    registry.insert("A", "D", Box::new(synthetic_field(Point3dd([0.0; 3]))));
+    assert!(registry.path("D", "A").is_none());
+    assert!(registry.transform_points("D", "A", &points).is_err());
+    assert!(registry.path("A", "Z").is_none());
+
+    Ok(())
+}
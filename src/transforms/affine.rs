@@ -6,6 +6,11 @@ use std::fs::File;
 use std::io::BufReader;
 use std::io::Read;
 
+use super::gis::Point3dd;
+use super::matrix;
+use super::matrix::Matrix;
+use super::region::BoundingBox;
+use super::Transform;
 // We tie this to GIS' restrictions for now.
 use super::K;
 
@@ -26,6 +31,33 @@ impl Debug for AffineTransform {
 }
 
 impl AffineTransform {
+    pub fn new(matrix: Matrix, offsets: [f64; K]) -> Self {
+        AffineTransform { offsets, matrix }
+    }
+
+    pub fn identity() -> Self {
+        Self::new(matrix::identity(), [0.; K])
+    }
+
+    pub fn matrix(&self) -> &Matrix {
+        &self.matrix
+    }
+
+    pub fn offsets(&self) -> &[f64; K] {
+        &self.offsets
+    }
+
+    // x -> M^-1 (x - offsets), None if the matrix is singular.
+    pub fn inverted(&self) -> Option<Self> {
+        let matrix = matrix::inverse(&self.matrix)?;
+        let mut offsets = matrix::apply(&matrix, &self.offsets);
+        for o in offsets.iter_mut() {
+            *o = -*o;
+        }
+
+        Some(Self::new(matrix, offsets))
+    }
+
     pub fn load_file(filename: &str) -> Result<Self, Box<dyn Error>> {
         let mut file_in = BufReader::new(File::open(&filename)?);
 
@@ -43,7 +75,9 @@ impl AffineTransform {
             })
             .collect::<Vec<_>>()[0];
 
+        // The rows of the matrix follow the offsets.
         let matrix = iter
+            .skip(1)
             .map(|line| {
                 let v = line
                     .split_whitespace()
@@ -61,6 +95,28 @@ impl AffineTransform {
     }
 }
 
+impl Transform for AffineTransform {
+    fn transform(&self, p: &Point3dd) -> Point3dd {
+        let mut t = Point3dd(matrix::apply(&self.matrix, &p.0));
+        for k in 0..K {
+            t.0[k] += self.offsets[k];
+        }
+
+        t
+    }
+
+    fn domain(&self) -> Option<BoundingBox> {
+        None
+    }
+
+    fn inverse(&self) -> Option<Box<dyn Transform>> {
+        match self.inverted() {
+            Some(t) => Some(Box::new(t)),
+            None => None,
+        }
+    }
+}
+
 pub fn load_file(basename: &str) -> Result<AffineTransform, Box<dyn Error>> {
     AffineTransform::load_file(basename)
 }
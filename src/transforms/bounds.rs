@@ -0,0 +1,462 @@
+use super::gis::FieldMode;
+use super::gis::GISTransform;
+use super::gis::Point3dd;
+use super::region::BoundingBox;
+use super::K;
+
+// Trilinear interpolation computes convex combinations of the control
+// points of a cell, so the displacement anywhere in a set of cells is
+// bounded by the per-component extrema of their control points.
+//
+// Absolute fields clamp the position, not the displacement, in the last cell
+// of each axis: there the displacement along that axis decreases by up to
+// one spacing past the last control point, which is added to the extrema.
+//
+// Level 0 stores those extrema for blocks of `block_size`^3 cells, each
+// next level merges 2x2x2 blocks of the previous one, up to a single block.
+
+#[derive(Clone, Debug)]
+struct Block {
+    min: [f32; K],
+    max: [f32; K],
+    nan: bool, // At least one control point of the block is NaN
+}
+
+impl Block {
+    fn empty() -> Self {
+        Block {
+            min: [f32::INFINITY; K],
+            max: [f32::NEG_INFINITY; K],
+            nan: false,
+        }
+    }
+
+    fn merge(&mut self, other: &Block) {
+        for k in 0..K {
+            self.min[k] = self.min[k].min(other.min[k]);
+            self.max[k] = self.max[k].max(other.max[k]);
+        }
+        self.nan = self.nan || other.nan;
+    }
+
+    fn add_control(&mut self, field: &GISTransform, i: usize, j: usize, k: usize) {
+        let p = field.ctrl_displacement(i, j, k);
+        if p.is_nan() {
+            self.nan = true;
+            return;
+        }
+
+        let d = field.dimensions();
+        let s = field.spacing();
+        for (axis, index) in [i, j, k].iter().enumerate() {
+            let mut low = p[axis];
+            if field.mode() == FieldMode::Absolute && *index + 1 == d[axis] {
+                low -= s[axis] as f32;
+            }
+
+            self.min[axis] = self.min[axis].min(low);
+            self.max[axis] = self.max[axis].max(p[axis]);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Level {
+    blocks: [usize; K], // Number of blocks per axis
+    cells: usize,       // Number of cells per block side
+    data: Vec<Block>,
+}
+
+impl Level {
+    fn block(&self, i: usize, j: usize, k: usize) -> &Block {
+        &self.data[i + self.blocks[0] * (j + self.blocks[1] * k)]
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DisplacementRange {
+    pub min: Point3dd, // Expressed in millimeters
+    pub max: Point3dd,
+    pub nan: bool, // Part of the region may be deformed to NaN
+}
+
+#[derive(Clone, Debug)]
+pub struct SourceBlock {
+    pub region: BoundingBox, // Source region, in millimeters
+    pub image: BoundingBox,  // Encloses the deformation of the region
+}
+
+impl DisplacementRange {
+    // No valid displacement at all in the region.
+    pub fn is_empty(&self) -> bool {
+        (0..K).any(|k| self.min[k] > self.max[k])
+    }
+}
+
+#[derive(Debug)]
+pub struct DisplacementPyramid {
+    dimensions: [usize; K], // Number of cells, which equals the number of control points
+    spacing: [f64; K],
+    origin: Point3dd,
+    mode: FieldMode,
+    levels: Vec<Level>,
+}
+
+impl DisplacementPyramid {
+    pub fn new(field: &GISTransform, block_size: usize) -> Self {
+        let block_size = block_size.max(1);
+        let d = field.dimensions();
+        let s = field.spacing();
+        let dimensions = [d[0], d[1], d[2]];
+        let spacing = [s[0], s[1], s[2]];
+
+        let mut levels = vec![Self::base_level(field, &dimensions, block_size)];
+
+        while levels.last().unwrap().blocks.iter().any(|&b| b > 1) {
+            let next = Self::merge_level(levels.last().unwrap());
+            levels.push(next);
+        }
+
+        DisplacementPyramid {
+            dimensions,
+            spacing,
+            origin: field.origin().clone(),
+            mode: field.mode(),
+            levels,
+        }
+    }
+
+    // Whether the pyramid may have been built from the field, that is
+    // whether both share the same grid and mode.
+    pub fn matches(&self, field: &GISTransform) -> bool {
+        let d = field.dimensions();
+        let s = field.spacing();
+        let o = field.origin();
+
+        self.mode == field.mode()
+            && (0..K).all(|k| {
+                self.dimensions[k] == d[k] && self.spacing[k] == s[k] && self.origin[k] == o[k]
+            })
+    }
+
+    fn base_level(field: &GISTransform, dimensions: &[usize; K], block_size: usize) -> Level {
+        let mut blocks = [0; K];
+        for k in 0..K {
+            blocks[k] = dimensions[k].div_ceil(block_size);
+        }
+
+        let mut data = Vec::with_capacity(blocks[0] * blocks[1] * blocks[2]);
+
+        for bz in 0..blocks[2] {
+            for by in 0..blocks[1] {
+                for bx in 0..blocks[0] {
+                    let b = [bx, by, bz];
+                    let mut low = [0; K];
+                    let mut high = [0; K];
+
+                    // The last cell of a block also depends on the first
+                    // control point of the next block.
+                    for k in 0..K {
+                        low[k] = b[k] * block_size;
+                        high[k] = ((b[k] + 1) * block_size).min(dimensions[k] - 1);
+                    }
+
+                    let mut block = Block::empty();
+                    for z in low[2]..=high[2] {
+                        for y in low[1]..=high[1] {
+                            for x in low[0]..=high[0] {
+                                block.add_control(field, x, y, z);
+                            }
+                        }
+                    }
+
+                    data.push(block);
+                }
+            }
+        }
+
+        Level {
+            blocks,
+            cells: block_size,
+            data,
+        }
+    }
+
+    fn merge_level(previous: &Level) -> Level {
+        let mut blocks = previous.blocks;
+        for b in blocks.iter_mut() {
+            *b = b.div_ceil(2);
+        }
+
+        let mut data = Vec::with_capacity(blocks[0] * blocks[1] * blocks[2]);
+
+        for bz in 0..blocks[2] {
+            for by in 0..blocks[1] {
+                for bx in 0..blocks[0] {
+                    let mut block = Block::empty();
+
+                    for z in (bz * 2)..(bz * 2 + 2).min(previous.blocks[2]) {
+                        for y in (by * 2)..(by * 2 + 2).min(previous.blocks[1]) {
+                            for x in (bx * 2)..(bx * 2 + 2).min(previous.blocks[0]) {
+                                block.merge(previous.block(x, y, z));
+                            }
+                        }
+                    }
+
+                    data.push(block);
+                }
+            }
+        }
+
+        Level {
+            blocks,
+            cells: previous.cells * 2,
+            data,
+        }
+    }
+
+    pub fn levels(&self) -> usize {
+        self.levels.len()
+    }
+
+    // Deformation field domain, in millimeters.
+    pub fn domain(&self) -> BoundingBox {
+        let mut high = self.origin.clone();
+        for k in 0..K {
+            high.0[k] += self.dimensions[k] as f64 * self.spacing[k];
+        }
+
+        BoundingBox::new(self.origin.clone(), high)
+    }
+
+    // Inclusive range of cells covering the region, None if disjoint
+    // from the deformation field.
+    fn cell_range(&self, region: &BoundingBox) -> Option<([usize; K], [usize; K])> {
+        let mut low = [0; K];
+        let mut high = [0; K];
+
+        for k in 0..K {
+            let l = ((region.low[k] - self.origin[k]) / self.spacing[k]).floor();
+            let h = ((region.high[k] - self.origin[k]) / self.spacing[k]).floor();
+            let max = (self.dimensions[k] - 1) as f64;
+
+            if h < 0.0 || l > max || l > h {
+                return None;
+            }
+
+            low[k] = l.max(0.0) as usize;
+            high[k] = h.min(max) as usize;
+        }
+
+        Some((low, high))
+    }
+
+    // Conservative displacement extrema over the given cells, inclusive.
+    fn cells_range(&self, low: &[usize; K], high: &[usize; K]) -> Block {
+        let mut range = Block::empty();
+        let top = self.levels.len() - 1;
+        let mut stack = vec![(top, [0usize; K])];
+
+        while let Some((level, b)) = stack.pop() {
+            let l = &self.levels[level];
+            let mut first = [0; K];
+            let mut last = [0; K];
+            let mut disjoint = false;
+            let mut contained = true;
+
+            for k in 0..K {
+                first[k] = b[k] * l.cells;
+                last[k] = ((b[k] + 1) * l.cells).min(self.dimensions[k]) - 1;
+
+                disjoint = disjoint || last[k] < low[k] || first[k] > high[k];
+                contained = contained && low[k] <= first[k] && last[k] <= high[k];
+            }
+
+            if disjoint {
+                continue;
+            }
+
+            if contained || level == 0 {
+                range.merge(l.block(b[0], b[1], b[2]));
+                continue;
+            }
+
+            let children = &self.levels[level - 1].blocks;
+            for z in (b[2] * 2)..(b[2] * 2 + 2).min(children[2]) {
+                for y in (b[1] * 2)..(b[1] * 2 + 2).min(children[1]) {
+                    for x in (b[0] * 2)..(b[0] * 2 + 2).min(children[0]) {
+                        stack.push((level - 1, [x, y, z]));
+                    }
+                }
+            }
+        }
+
+        range
+    }
+
+    pub fn displacement_range(&self, region: &BoundingBox) -> DisplacementRange {
+        let domain = self.domain();
+        let mut nan =
+            (0..K).any(|k| region.low[k] < domain.low[k] || region.high[k] >= domain.high[k]);
+
+        let block = match self.cell_range(region) {
+            None => Block {
+                nan: true,
+                ..Block::empty()
+            },
+            Some((low, high)) => self.cells_range(&low, &high),
+        };
+        nan = nan || block.nan;
+
+        let mut range = DisplacementRange {
+            min: Point3dd([0.; K]),
+            max: Point3dd([0.; K]),
+            nan,
+        };
+        for k in 0..K {
+            range.min.0[k] = f64::from(block.min[k]);
+            range.max.0[k] = f64::from(block.max[k]);
+        }
+
+        range
+    }
+
+    // Box guaranteed to contain the deformation of every point of the
+    // region which does not deform to NaN. Empty if there is no such point.
+    pub fn enclosing_box(&self, region: &BoundingBox) -> BoundingBox {
+        let range = self.displacement_range(region);
+        if range.is_empty() {
+            return BoundingBox::empty();
+        }
+
+        let domain = self.domain();
+        let mut result = BoundingBox::empty();
+
+        for k in 0..K {
+            // Clip to the field as positions outside of it are deformed to NaN.
+            let low = region.low[k].max(domain.low[k]);
+            let high = region.high[k].min(domain.high[k]);
+
+            result.low.0[k] = outward(low + range.min[k], -1.0);
+            result.high.0[k] = outward(high + range.max[k], 1.0);
+        }
+
+        result
+    }
+
+    // Source region covered by the given cells, inclusive, and the
+    // conservative image of that region.
+    fn block_image(&self, first: &[usize; K], last: &[usize; K], range: &Block) -> SourceBlock {
+        let mut block = SourceBlock {
+            region: BoundingBox::empty(),
+            image: BoundingBox::empty(),
+        };
+
+        for k in 0..K {
+            block.region.low.0[k] = self.origin[k] + first[k] as f64 * self.spacing[k];
+            block.region.high.0[k] = self.origin[k] + (last[k] + 1) as f64 * self.spacing[k];
+
+            if range.min[k] <= range.max[k] {
+                block.image.low.0[k] = outward(block.region.low[k] + f64::from(range.min[k]), -1.0);
+                block.image.high.0[k] =
+                    outward(block.region.high[k] + f64::from(range.max[k]), 1.0);
+            }
+        }
+
+        block
+    }
+
+    // Level 0 blocks which may contain positions deformed into the target.
+    //
+    // This is conservative: every source position whose deformation is in
+    // the target belongs to one of the returned blocks, the opposite does
+    // not hold.
+    pub fn preimage_blocks(&self, target: &BoundingBox) -> Vec<SourceBlock> {
+        let mut blocks = vec![];
+        let top = self.levels.len() - 1;
+        let mut stack = vec![(top, [0usize; K])];
+
+        while let Some((level, b)) = stack.pop() {
+            let l = &self.levels[level];
+            let mut first = [0; K];
+            let mut last = [0; K];
+
+            for k in 0..K {
+                first[k] = b[k] * l.cells;
+                last[k] = ((b[k] + 1) * l.cells).min(self.dimensions[k]) - 1;
+            }
+
+            let block = self.block_image(&first, &last, l.block(b[0], b[1], b[2]));
+            if block.image.is_empty() || !block.image.intersects(target) {
+                continue;
+            }
+
+            if level == 0 {
+                blocks.push(block);
+                continue;
+            }
+
+            let children = &self.levels[level - 1].blocks;
+            for z in (b[2] * 2)..(b[2] * 2 + 2).min(children[2]) {
+                for y in (b[1] * 2)..(b[1] * 2 + 2).min(children[1]) {
+                    for x in (b[0] * 2)..(b[0] * 2 + 2).min(children[0]) {
+                        stack.push((level - 1, [x, y, z]));
+                    }
+                }
+            }
+        }
+
+        blocks
+    }
+
+    // Same as `preimage_blocks`, refined down to single cells using the
+    // control points of the deformation field the pyramid was built from.
+    pub fn preimage_voxels(&self, field: &GISTransform, target: &BoundingBox) -> Vec<SourceBlock> {
+        let mut voxels = vec![];
+
+        for block in self.preimage_blocks(target) {
+            let (low, high) = match self.cell_range(&block.region) {
+                Some(range) => range,
+                None => continue,
+            };
+
+            for z in low[2]..=high[2] {
+                for y in low[1]..=high[1] {
+                    for x in low[0]..=high[0] {
+                        let cell = [x, y, z];
+
+                        // Skip the cells shared with the next block, they are
+                        // handled there.
+                        if (0..K).any(|k| {
+                            self.origin[k] + cell[k] as f64 * self.spacing[k]
+                                >= block.region.high[k]
+                        }) {
+                            continue;
+                        }
+
+                        let mut range = Block::empty();
+                        for k_z in z..=(z + 1).min(self.dimensions[2] - 1) {
+                            for k_y in y..=(y + 1).min(self.dimensions[1] - 1) {
+                                for k_x in x..=(x + 1).min(self.dimensions[0] - 1) {
+                                    range.add_control(field, k_x, k_y, k_z);
+                                }
+                            }
+                        }
+
+                        let voxel = self.block_image(&cell, &cell, &range);
+                        if !voxel.image.is_empty() && voxel.image.intersects(target) {
+                            voxels.push(voxel);
+                        }
+                    }
+                }
+            }
+        }
+
+        voxels
+    }
+}
+
+// Widen a bound by a few ULPs to absorb rounding errors of the interpolation.
+fn outward(v: f64, direction: f64) -> f64 {
+    v + direction * 8.0 * f64::EPSILON * v.abs().max(1.0)
+}
@@ -0,0 +1,166 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::Read;
+use std::path::Path;
+
+use super::affine::AffineTransform;
+use super::compose;
+use super::compose::ApproximationError;
+use super::gis::GISTransform;
+use super::gis::Point3dd;
+use super::region::BoundingBox;
+use super::Transform;
+use super::K;
+
+// Ordered list of transforms, applied from the first to the last.
+//
+// As soon as one step maps a point to NaN, or the point is outside of the
+// domain of the next step, the whole chain returns NaN for that point.
+#[derive(Debug, Default)]
+pub struct TransformChain {
+    steps: Vec<Box<dyn Transform>>,
+}
+
+impl TransformChain {
+    pub fn new() -> Self {
+        TransformChain { steps: vec![] }
+    }
+
+    // Append a step, replaced by its inverse when `inverted` is set.
+    pub fn push(
+        &mut self,
+        transform: Box<dyn Transform>,
+        inverted: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let step = if inverted {
+            match transform.inverse() {
+                Some(t) => t,
+                None => return Err(format!("No inverse available for {:?}", transform).into()),
+            }
+        } else {
+            transform
+        };
+
+        self.steps.push(step);
+
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    pub fn steps(&self) -> &[Box<dyn Transform>] {
+        &self.steps
+    }
+
+    // Precompute the whole chain as a single field, see `compose::bake`.
+    pub fn bake(
+        &self,
+        extent: &BoundingBox,
+        spacing: Point3dd,
+        validation: usize,
+    ) -> (GISTransform, ApproximationError) {
+        compose::bake(self, extent, spacing, validation)
+    }
+
+    // Manifest format, one step per line, applied in order:
+    //
+    //      # Comment
+    //      affine  <file.trm>  [inverse]
+    //      gis     <basename>
+    //
+    // Relative paths are resolved from the directory of the manifest.
+    pub fn load_file(filename: &str) -> Result<Self, Box<dyn Error>> {
+        let mut file_in = BufReader::new(File::open(filename)?);
+
+        let mut string = String::new();
+        file_in.read_to_string(&mut string)?;
+
+        let directory = Path::new(filename)
+            .parent()
+            .unwrap_or_else(|| Path::new(""));
+        let mut chain = Self::new();
+
+        for (n, line) in string.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let values = line.split_whitespace().collect::<Vec<_>>();
+            let inverted = match values.get(2) {
+                None => false,
+                Some(&"inverse") => true,
+                Some(v) => {
+                    return Err(format!("{}:{}: unknown option '{}'", filename, n + 1, v).into())
+                }
+            };
+
+            let path = match values.get(1) {
+                Some(v) => directory.join(v),
+                None => return Err(format!("{}:{}: missing file name", filename, n + 1).into()),
+            };
+            let path = path.to_string_lossy();
+
+            let step: Box<dyn Transform> = match values[0] {
+                "affine" => Box::new(AffineTransform::load_file(&path)?),
+                "gis" => Box::new(GISTransform::load_file(&path)?),
+                v => {
+                    return Err(format!("{}:{}: unknown transform '{}'", filename, n + 1, v).into())
+                }
+            };
+
+            chain.push(step, inverted)?;
+        }
+
+        Ok(chain)
+    }
+}
+
+impl Transform for TransformChain {
+    fn transform(&self, p: &Point3dd) -> Point3dd {
+        let mut t = p.clone();
+
+        for step in &self.steps {
+            if let Some(domain) = step.domain() {
+                if !domain.contains(&t) {
+                    return Point3dd([f64::NAN; K]);
+                }
+            }
+
+            t = step.transform(&t);
+            if t.is_nan() {
+                return Point3dd([f64::NAN; K]);
+            }
+        }
+
+        t
+    }
+
+    fn domain(&self) -> Option<BoundingBox> {
+        match self.steps.first() {
+            Some(step) => step.domain(),
+            None => None,
+        }
+    }
+
+    fn inverse(&self) -> Option<Box<dyn Transform>> {
+        let mut chain = Self::new();
+
+        for step in self.steps.iter().rev() {
+            chain.steps.push(step.inverse()?);
+        }
+
+        Some(Box::new(chain))
+    }
+}
+
+pub fn load_file(filename: &str) -> Result<TransformChain, Box<dyn Error>> {
+    TransformChain::load_file(filename)
+}
@@ -0,0 +1,134 @@
+use super::affine::AffineTransform;
+use super::gis::GISTransform;
+use super::gis::Point3dd;
+use super::grid::Grid;
+use super::region::BoundingBox;
+use super::Transform;
+use super::K;
+
+#[derive(Clone, Debug, Default)]
+pub struct ApproximationError {
+    pub samples: usize,      // Validation positions defined by both transforms
+    pub nan_mismatch: usize, // Validation positions defined by only one of them
+    pub max: f64,            // Euclidean distance, in millimeters
+    pub mean: f64,
+    pub rms: f64,
+}
+
+// Displacement field of `f` on the grid, positions mapped to NaN are kept as NaN.
+fn sample_with<F>(grid: &Grid, f: F) -> GISTransform
+where
+    F: Fn(&Point3dd) -> Point3dd,
+{
+    let d = &grid.dimensions;
+    let mut displacements = Vec::with_capacity(grid.len() * K);
+
+    for z in 0..d[2] {
+        for y in 0..d[1] {
+            for x in 0..d[0] {
+                let p = grid.position(x, y, z);
+                let t = f(&p);
+
+                for k in 0..K {
+                    displacements.push((t[k] - p[k]) as f32);
+                }
+            }
+        }
+    }
+
+    // The number of values matches the grid by construction.
+    GISTransform::from_grid(grid, displacements).unwrap()
+}
+
+// Resample any transform as a displacement field on the given grid.
+pub fn sample(transform: &dyn Transform, grid: &Grid) -> GISTransform {
+    sample_with(grid, |p| transform.transform(p))
+}
+
+// Single field equivalent to applying `first`, then the affine transforms
+// in order, then `second`, resampled on the given grid.
+pub fn compose(
+    first: &GISTransform,
+    between: &[&AffineTransform],
+    second: &GISTransform,
+    grid: &Grid,
+) -> GISTransform {
+    sample_with(grid, |p| {
+        let mut t = first.deformation(p);
+        for affine in between {
+            t = affine.transform(&t);
+        }
+
+        second.deformation(&t)
+    })
+}
+
+// Bake a transform, typically a chain, into a single field covering the
+// extent with the given spacing.
+//
+// The result is compared to the exact transform on `validation` positions
+// spread over the extent, using a Halton sequence, which is deterministic.
+pub fn bake(
+    transform: &dyn Transform,
+    extent: &BoundingBox,
+    spacing: Point3dd,
+    validation: usize,
+) -> (GISTransform, ApproximationError) {
+    let grid = Grid::covering(extent, spacing);
+    let field = sample(transform, &grid);
+
+    let mut error = ApproximationError::default();
+    let mut sum = 0.0;
+    let mut sum_squares = 0.0;
+    let size = extent.extent();
+
+    for i in 1..=validation {
+        let mut p = extent.low.clone();
+        for (k, base) in [2, 3, 5].iter().enumerate() {
+            p.0[k] += halton(i, *base) * size[k];
+        }
+
+        let exact = transform.transform(&p);
+        let approximation = field.deformation(&p);
+
+        match (exact.is_nan(), approximation.is_nan()) {
+            (true, true) => continue,
+            (false, false) => (),
+            _ => {
+                error.nan_mismatch += 1;
+                continue;
+            }
+        }
+
+        let distance = (0..K)
+            .map(|k| (exact[k] - approximation[k]).powi(2))
+            .sum::<f64>()
+            .sqrt();
+
+        error.samples += 1;
+        error.max = error.max.max(distance);
+        sum += distance;
+        sum_squares += distance * distance;
+    }
+
+    if error.samples > 0 {
+        error.mean = sum / error.samples as f64;
+        error.rms = (sum_squares / error.samples as f64).sqrt();
+    }
+
+    (field, error)
+}
+
+// Element `index` of the van der Corput sequence in `base`, in [0, 1).
+fn halton(mut index: usize, base: usize) -> f64 {
+    let mut result = 0.0;
+    let mut f = 1.0;
+
+    while index > 0 {
+        f /= base as f64;
+        result += f * (index % base) as f64;
+        index /= base;
+    }
+
+    result
+}
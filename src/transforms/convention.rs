@@ -0,0 +1,175 @@
+use super::affine::AffineTransform;
+use super::gis::GISTransform;
+use super::gis::Point3dd;
+use super::matrix;
+use super::oriented::OrientedField;
+use super::region::BoundingBox;
+use super::Transform;
+use super::K;
+
+// Orientation of the world axes: RAS is used by NIfTI, FSL and SPM, LPS by
+// ITK and ANTs, and LPI by AIMS, whose GIS fields are expressed in the
+// millimeter referential of the image, x going to the left, y to the back
+// and z to the feet.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Convention {
+    Ras,
+    Lps,
+    Lpi,
+}
+
+impl Convention {
+    // Sign of each axis with respect to RAS.
+    pub fn signs(self) -> [f64; K] {
+        match self {
+            Convention::Ras => [1.0, 1.0, 1.0],
+            Convention::Lps => [-1.0, -1.0, 1.0],
+            Convention::Lpi => [-1.0, -1.0, -1.0],
+        }
+    }
+
+    // Change of coordinates of points, and vectors, from self to `to`.
+    pub fn change(self, to: Convention) -> AffineTransform {
+        let (a, b) = (self.signs(), to.signs());
+        let mut matrix = [[0f64; K]; K];
+        for (k, row) in matrix.iter_mut().enumerate() {
+            row[k] = a[k] * b[k];
+        }
+
+        AffineTransform::new(matrix, [0.; K])
+    }
+
+    pub fn convert(self, to: Convention, p: &Point3dd) -> Point3dd {
+        let (a, b) = (self.signs(), to.signs());
+
+        Point3dd([p[0] * a[0] * b[0], p[1] * a[1] * b[1], p[2] * a[2] * b[2]])
+    }
+}
+
+// Transform defined in one referential, the inner one, used with points of
+// another, the outer one: points are moved to the inner referential, mapped,
+// and moved back.
+#[derive(Debug)]
+pub struct Converted {
+    transform: Box<dyn Transform>,
+    to_inner: AffineTransform,
+    to_outer: AffineTransform,
+}
+
+impl Converted {
+    pub fn new(transform: Box<dyn Transform>, inner: Convention, outer: Convention) -> Self {
+        Converted {
+            transform,
+            to_inner: outer.change(inner),
+            to_outer: inner.change(outer),
+        }
+    }
+
+    // Arbitrary change of referential, for instance to go from world
+    // coordinates to the millimeter referential of an AIMS image, which
+    // also depends on the position of the image. None if singular.
+    pub fn with_frame(transform: Box<dyn Transform>, to_inner: AffineTransform) -> Option<Self> {
+        let to_outer = to_inner.inverted()?;
+
+        Some(Converted {
+            transform,
+            to_inner,
+            to_outer,
+        })
+    }
+
+    pub fn inner(&self) -> &dyn Transform {
+        self.transform.as_ref()
+    }
+}
+
+impl Transform for Converted {
+    fn transform(&self, p: &Point3dd) -> Point3dd {
+        let t = self.transform.transform(&self.to_inner.transform(p));
+        if t.is_nan() {
+            return t;
+        }
+
+        self.to_outer.transform(&t)
+    }
+
+    fn transform_batch(&self, points: &[Point3dd]) -> Vec<Point3dd> {
+        let inner = points
+            .iter()
+            .map(|p| self.to_inner.transform(p))
+            .collect::<Vec<_>>();
+
+        self.transform
+            .transform_batch(&inner)
+            .iter()
+            .map(|t| {
+                if t.is_nan() {
+                    t.clone()
+                } else {
+                    self.to_outer.transform(t)
+                }
+            })
+            .collect()
+    }
+
+    fn domain(&self) -> Option<BoundingBox> {
+        let domain = self.transform.domain()?;
+        let mut b = BoundingBox::empty();
+
+        for corner in 0..(1 << K) {
+            let mut p = domain.low.clone();
+            for k in 0..K {
+                if corner & (1 << k) != 0 {
+                    p.0[k] = domain.high[k];
+                }
+            }
+            b.extend(&self.to_outer.transform(&p));
+        }
+
+        Some(b)
+    }
+
+    fn inverse(&self) -> Option<Box<dyn Transform>> {
+        let to_inner = AffineTransform::new(*self.to_inner.matrix(), *self.to_inner.offsets());
+
+        Some(Box::new(Converted::with_frame(
+            self.transform.inverse()?,
+            to_inner,
+        )?))
+    }
+}
+
+impl OrientedField {
+    // Same field, its world coordinates, displacements and absolute positions
+    // being moved from the `from` convention to the `to` one.
+    pub fn change_convention(&self, from: Convention, to: Convention) -> OrientedField {
+        let change = from.change(to);
+        let signs = from.convert(to, &Point3dd([1.; K]));
+
+        let field = self.field();
+        let grid = field.grid();
+        let d = grid.dimensions;
+        let mut values = Vec::with_capacity(grid.len() * K);
+        for z in 0..d[2] {
+            for y in 0..d[1] {
+                for x in 0..d[0] {
+                    let v = field.ctrl_point(x, y, z);
+                    for k in 0..K {
+                        values.push(v[k] * signs[k] as f32);
+                    }
+                }
+            }
+        }
+
+        let w = self.to_world();
+        let matrix = matrix::multiply(change.matrix(), w.matrix());
+        let offsets = change.transform(&Point3dd(*w.offsets()));
+
+        // The number of values matches the grid by construction, and the
+        // change of convention keeps the orientation invertible.
+        let field = GISTransform::from_grid(&grid, values)
+            .unwrap()
+            .with_mode(field.mode());
+        OrientedField::new(field, AffineTransform::new(matrix, offsets.0)).unwrap()
+    }
+}
@@ -0,0 +1,71 @@
+use std::error::Error;
+
+use super::gis::GISTransform;
+use super::gis::Point3dd;
+use super::grid::Grid;
+use super::region::BoundingBox;
+use super::K;
+
+impl GISTransform {
+    // Keep the cells from `low` included to `high` excluded, expressed in
+    // voxels. The control points bounding those cells are copied, and the
+    // origin moved, so that `deformation` gives the same results as the
+    // original field on the cells, up to rounding errors.
+    pub fn crop_voxels(&self, low: [usize; K], high: [usize; K]) -> Result<Self, Box<dyn Error>> {
+        let d = self.dimensions();
+        let mut last = [0; K];
+        let mut dimensions = [0; K];
+
+        for k in 0..K {
+            if low[k] >= high[k] || low[k] >= d[k] {
+                return Err(
+                    format!("Invalid crop [{:?}, {:?}) of a {:?} field", low, high, d).into(),
+                );
+            }
+
+            last[k] = high[k].min(d[k] - 1);
+            dimensions[k] = last[k] - low[k] + 1;
+        }
+
+        let s = self.spacing();
+        let mut origin = self.origin().clone();
+        for k in 0..K {
+            origin.0[k] += low[k] as f64 * s[k];
+        }
+
+        let grid = Grid::new(origin, Point3dd([s[0], s[1], s[2]]), dimensions);
+        let mut displacements = Vec::with_capacity(grid.len() * K);
+
+        for z in low[2]..=last[2] {
+            for y in low[1]..=last[1] {
+                for x in low[0]..=last[0] {
+                    let p = self.ctrl_point(x, y, z);
+                    for k in 0..K {
+                        displacements.push(p[k]);
+                    }
+                }
+            }
+        }
+
+        Ok(GISTransform::from_grid(&grid, displacements)?.with_mode(self.mode()))
+    }
+
+    // Keep the cells intersecting the box, expressed in millimeters.
+    pub fn crop_mm(&self, region: &BoundingBox) -> Result<Self, Box<dyn Error>> {
+        let d = self.dimensions();
+        let s = self.spacing();
+        let o = self.origin();
+        let mut low = [0; K];
+        let mut high = [0; K];
+
+        for k in 0..K {
+            let l = ((region.low[k] - o[k]) / s[k]).floor();
+            let h = ((region.high[k] - o[k]) / s[k]).floor() + 1.0;
+
+            low[k] = l.max(0.0).min(d[k] as f64) as usize;
+            high[k] = h.max(0.0).min(d[k] as f64) as usize;
+        }
+
+        self.crop_voxels(low, high)
+    }
+}
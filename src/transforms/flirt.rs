@@ -0,0 +1,183 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+
+use super::affine::AffineTransform;
+use super::gis::Point3dd;
+use super::matrix;
+use super::nifti;
+use super::Transform;
+use super::K;
+
+// Geometry of an image, as used by FSL: its number of voxels along each
+// axis, and the mapping of its voxel indices to world coordinates.
+#[derive(Debug)]
+pub struct Geometry {
+    dimensions: [usize; K],
+    to_world: AffineTransform,
+}
+
+impl Geometry {
+    pub fn new(dimensions: [usize; K], to_world: AffineTransform) -> Self {
+        Geometry {
+            dimensions,
+            to_world,
+        }
+    }
+
+    // Geometry of a NIfTI image.
+    pub fn load_file(filename: &str) -> Result<Self, Box<dyn Error>> {
+        let header = nifti::read_header(filename)?;
+
+        let mut dimensions = [1usize; K];
+        for (d, h) in dimensions.iter_mut().zip(&header.dimensions) {
+            *d = *h;
+        }
+
+        Ok(Self::new(dimensions, header.to_world))
+    }
+
+    pub fn dimensions(&self) -> &[usize; K] {
+        &self.dimensions
+    }
+
+    pub fn to_world(&self) -> &AffineTransform {
+        &self.to_world
+    }
+
+    // Voxel indices to the scaled voxel coordinates of FSL, in millimeters,
+    // whose x axis is flipped when the image is in neurological convention,
+    // that is when the determinant of its orientation is positive.
+    fn to_fsl(&self) -> AffineTransform {
+        let m = self.to_world.matrix();
+        let mut scaling = [[0f64; K]; K];
+        let mut offsets = [0f64; K];
+
+        for (k, row) in scaling.iter_mut().enumerate() {
+            row[k] = (0..K).map(|r| m[r][k] * m[r][k]).sum::<f64>().sqrt();
+        }
+        if matrix::determinant(m) > 0.0 {
+            offsets[0] = (self.dimensions[0] as f64 - 1.0) * scaling[0][0];
+            scaling[0][0] = -scaling[0][0];
+        }
+
+        AffineTransform::new(scaling, offsets)
+    }
+
+    // World coordinates to the scaled voxel coordinates, None if singular.
+    fn world_to_fsl(&self) -> Option<AffineTransform> {
+        Some(compose(&self.to_fsl(), &self.to_world.inverted()?))
+    }
+}
+
+// a o b, that is b followed by a.
+fn compose(a: &AffineTransform, b: &AffineTransform) -> AffineTransform {
+    let matrix = matrix::multiply(a.matrix(), b.matrix());
+    let offsets = a.transform(&Point3dd(*b.offsets()));
+
+    AffineTransform::new(matrix, offsets.0)
+}
+
+// A FLIRT matrix maps the scaled voxel coordinates of the source image to
+// those of the reference image. Convert it into the same mapping of the
+// world coordinates of both images.
+pub fn to_world(
+    flirt: &AffineTransform,
+    source: &Geometry,
+    reference: &Geometry,
+) -> Result<AffineTransform, Box<dyn Error>> {
+    let from_source = match source.world_to_fsl() {
+        Some(t) => t,
+        None => return Err(format!("Singular orientation {:?}", source.to_world).into()),
+    };
+    let to_reference = match reference.world_to_fsl().and_then(|t| t.inverted()) {
+        Some(t) => t,
+        None => return Err(format!("Singular orientation {:?}", reference.to_world).into()),
+    };
+
+    Ok(compose(&to_reference, &compose(flirt, &from_source)))
+}
+
+// Inverse of `to_world`: the FLIRT matrix of a mapping of the world
+// coordinates of the source image to those of the reference image.
+pub fn from_world(
+    transform: &AffineTransform,
+    source: &Geometry,
+    reference: &Geometry,
+) -> Result<AffineTransform, Box<dyn Error>> {
+    let to_source = match source.world_to_fsl().and_then(|t| t.inverted()) {
+        Some(t) => t,
+        None => return Err(format!("Singular orientation {:?}", source.to_world).into()),
+    };
+    let from_reference = match reference.world_to_fsl() {
+        Some(t) => t,
+        None => return Err(format!("Singular orientation {:?}", reference.to_world).into()),
+    };
+
+    Ok(compose(&from_reference, &compose(transform, &to_source)))
+}
+
+// Load a FLIRT .mat file, 4 rows of 4 values, as a mapping of the world
+// coordinates of the source image to those of the reference image. Its
+// inverse is the one to use to resample the source image on the reference.
+pub fn load_file(
+    filename: &str,
+    source: &Geometry,
+    reference: &Geometry,
+) -> Result<AffineTransform, Box<dyn Error>> {
+    let mut file_in = BufReader::new(File::open(filename)?);
+
+    let mut string = String::new();
+    file_in.read_to_string(&mut string)?;
+
+    let values = match string
+        .split_whitespace()
+        .map(|v| v.parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(values) => values,
+        Err(e) => return Err(format!("{}: invalid value: {}", filename, e).into()),
+    };
+    if values.len() != (K + 1) * (K + 1) {
+        return Err(format!(
+            "{}: expected a {}x{} matrix, got {} values",
+            filename,
+            K + 1,
+            K + 1,
+            values.len()
+        )
+        .into());
+    }
+
+    let mut matrix = [[0f64; K]; K];
+    let mut offsets = [0f64; K];
+    for (r, row) in matrix.iter_mut().enumerate() {
+        row.copy_from_slice(&values[r * (K + 1)..r * (K + 1) + K]);
+        offsets[r] = values[r * (K + 1) + K];
+    }
+
+    to_world(&AffineTransform::new(matrix, offsets), source, reference)
+}
+
+// Save a mapping of world coordinates as a FLIRT .mat file.
+pub fn save_file(
+    filename: &str,
+    transform: &AffineTransform,
+    source: &Geometry,
+    reference: &Geometry,
+) -> Result<(), Box<dyn Error>> {
+    let flirt = from_world(transform, source, reference)?;
+    let (m, t) = (flirt.matrix(), flirt.offsets());
+
+    let mut file_out = BufWriter::new(File::create(filename)?);
+    for r in 0..K {
+        writeln!(file_out, "{}  {}  {}  {}", m[r][0], m[r][1], m[r][2], t[r])?;
+    }
+    writeln!(file_out, "0  0  0  1")?;
+    file_out.flush()?;
+
+    Ok(())
+}
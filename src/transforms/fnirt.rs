@@ -0,0 +1,240 @@
+use std::error::Error;
+
+use super::affine::AffineTransform;
+use super::gis::Point3dd;
+use super::matrix;
+use super::nifti;
+use super::region::BoundingBox;
+use super::Transform;
+use super::K;
+
+// Intent code of the coefficient files written by FNIRT with --cout.
+pub const INTENT_CUBIC_SPLINE: i32 = 2007;
+
+// Cubic B-spline, centered on 0, with a support of [-2, 2].
+fn bspline(t: f64) -> f64 {
+    let t = t.abs();
+    if t < 1.0 {
+        2.0 / 3.0 - t * t + t * t * t / 2.0
+    } else if t < 2.0 {
+        (2.0 - t).powi(3) / 6.0
+    } else {
+        0.0
+    }
+}
+
+// Displacement field of FNIRT, stored as the coefficients of cubic B-splines
+// whose knots are every `knot_spacing` voxels of the reference image.
+//
+// Coefficient i along an axis is centered on voxel (i - 1) * knot_spacing of
+// the reference image, so that there is one knot before the first voxel, and
+// two after the last one. The displacements are expressed in millimeters,
+// in the scaled voxel coordinates used by FSL, whose x axis is flipped when
+// the reference image is in neurological convention.
+//
+// This only covers the non-linear part of the warp, the affine part given
+// to FNIRT with --aff has to be applied after it.
+#[derive(Debug)]
+pub struct SplineField {
+    dimensions: [usize; K],      // Number of coefficients along each axis
+    knot_spacing: [f64; K],      // Expressed in voxels of the reference image
+    coefficients: Vec<[f64; K]>, // x varying fastest
+    to_world: AffineTransform,   // Voxels of the reference image to world coordinates
+    to_voxel: AffineTransform,
+    // Sign of the FSL axes with respect to the voxel axes
+    to_fsl: [f64; K],
+}
+
+impl SplineField {
+    pub fn new(
+        dimensions: [usize; K],
+        knot_spacing: [f64; K],
+        coefficients: Vec<[f64; K]>,
+        to_world: AffineTransform,
+    ) -> Result<Self, Box<dyn Error>> {
+        if coefficients.len() != dimensions.iter().product::<usize>() {
+            return Err(format!(
+                "Expected {} coefficients for a {:?} grid, got {}",
+                dimensions.iter().product::<usize>(),
+                dimensions,
+                coefficients.len()
+            )
+            .into());
+        }
+
+        let to_voxel = match to_world.inverted() {
+            Some(t) => t,
+            None => return Err(format!("Singular orientation {:?}", to_world).into()),
+        };
+
+        let mut to_fsl = [1.0; K];
+        if matrix::determinant(to_world.matrix()) > 0.0 {
+            to_fsl[0] = -1.0;
+        }
+
+        Ok(SplineField {
+            dimensions,
+            knot_spacing,
+            coefficients,
+            to_world,
+            to_voxel,
+            to_fsl,
+        })
+    }
+
+    // Load a coefficient file. Its dimensions are the number of coefficients
+    // for each of the 3 components, its voxel sizes the knot spacing, and
+    // its intent parameters the voxel size of the reference image.
+    pub fn load_file(filename: &str) -> Result<Self, Box<dyn Error>> {
+        let (header, values) = nifti::read_file(filename)?;
+
+        if header.intent != INTENT_CUBIC_SPLINE {
+            return Err(format!(
+                "{}: expected cubic spline coefficients, got intent code {}",
+                filename, header.intent
+            )
+            .into());
+        }
+
+        let mut d = header.dimensions.clone();
+        d.resize(K + 1, 1);
+        if d[K] != K || header.dimensions.len() > K + 1 {
+            return Err(format!(
+                "{}: expected {} volumes of coefficients, got dimensions {:?}",
+                filename, K, header.dimensions
+            )
+            .into());
+        }
+
+        if header.parameters.iter().any(|&p| p <= 0.0) {
+            return Err(format!("{}: missing voxel size of the reference image", filename).into());
+        }
+
+        let count = d[0] * d[1] * d[2];
+        let coefficients = (0..count)
+            .map(|i| [values[i], values[count + i], values[2 * count + i]])
+            .collect::<Vec<_>>();
+
+        // The orientation of the file is the one of the reference image, but
+        // its voxel sizes are replaced by the knot spacing, so scale the
+        // axes back to the voxel size of the reference.
+        let m = header.to_world.matrix();
+        let mut matrix = [[0f64; K]; K];
+        for c in 0..K {
+            let norm = (0..K).map(|r| m[r][c] * m[r][c]).sum::<f64>().sqrt();
+            for r in 0..K {
+                matrix[r][c] = m[r][c] / norm * header.parameters[c];
+            }
+        }
+
+        let knot_spacing = [header.pixdim[1], header.pixdim[2], header.pixdim[3]];
+        let to_world = AffineTransform::new(matrix, *header.to_world.offsets());
+
+        Self::new([d[0], d[1], d[2]], knot_spacing, coefficients, to_world)
+    }
+
+    pub fn dimensions(&self) -> &[usize; K] {
+        &self.dimensions
+    }
+
+    pub fn knot_spacing(&self) -> &[f64; K] {
+        &self.knot_spacing
+    }
+
+    pub fn to_world(&self) -> &AffineTransform {
+        &self.to_world
+    }
+
+    // Displacement at voxel v of the reference image, in millimeters along
+    // the FSL axes, NaN where the coefficients do not cover v.
+    fn fsl_displacement(&self, v: &Point3dd) -> Point3dd {
+        let mut first = [0usize; K];
+        let mut weights = [[0f64; 4]; K];
+
+        for k in 0..K {
+            let u = v[k] / self.knot_spacing[k] + 1.0;
+            let i = u.floor();
+            if !(i >= 1.0 && i + 2.0 < self.dimensions[k] as f64) {
+                return Point3dd([f64::NAN; K]);
+            }
+
+            first[k] = i as usize - 1;
+            for (j, w) in weights[k].iter_mut().enumerate() {
+                *w = bspline(u - (first[k] + j) as f64);
+            }
+        }
+
+        let d = &self.dimensions;
+        let mut displacement = Point3dd([0.; K]);
+        for (z, wz) in weights[2].iter().enumerate() {
+            for (y, wy) in weights[1].iter().enumerate() {
+                for (x, wx) in weights[0].iter().enumerate() {
+                    let index = first[0] + x + d[0] * (first[1] + y + d[1] * (first[2] + z));
+                    for (k, c) in self.coefficients[index].iter().enumerate() {
+                        displacement.0[k] += wx * wy * wz * c;
+                    }
+                }
+            }
+        }
+
+        displacement
+    }
+
+    // Interpolated displacement at p, both in world coordinates.
+    pub fn displacement(&self, p: &Point3dd) -> Point3dd {
+        let v = self.to_voxel.transform(p);
+        let d = self.fsl_displacement(&v);
+        if d.is_nan() {
+            return d;
+        }
+
+        // FSL millimeters to voxels, then to world coordinates.
+        let m = self.to_world.matrix();
+        let mut step = [0f64; K];
+        for k in 0..K {
+            let size = (0..K).map(|r| m[r][k] * m[r][k]).sum::<f64>().sqrt();
+            step[k] = d[k] * self.to_fsl[k] / size;
+        }
+
+        Point3dd(matrix::apply(m, &step))
+    }
+
+    pub fn deformation(&self, p: &Point3dd) -> Point3dd {
+        let mut t = p.clone();
+
+        t += self.displacement(p);
+
+        t
+    }
+}
+
+impl Transform for SplineField {
+    fn transform(&self, p: &Point3dd) -> Point3dd {
+        self.deformation(p)
+    }
+
+    // Bounding box of the covered voxels, in world coordinates.
+    fn domain(&self) -> Option<BoundingBox> {
+        let mut b = BoundingBox::empty();
+
+        for corner in 0..(1 << K) {
+            let mut p = Point3dd([0.; K]);
+            for k in 0..K {
+                if corner & (1 << k) != 0 {
+                    p.0[k] = (self.dimensions[k] as f64 - 3.0) * self.knot_spacing[k];
+                }
+            }
+            b.extend(&self.to_world.transform(&p));
+        }
+
+        Some(b)
+    }
+
+    fn inverse(&self) -> Option<Box<dyn Transform>> {
+        None
+    }
+}
+
+pub fn load_file(filename: &str) -> Result<SplineField, Box<dyn Error>> {
+    SplineField::load_file(filename)
+}
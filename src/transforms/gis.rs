@@ -1,20 +1,37 @@
 use std::error::Error;
 use std::fmt;
 use std::fmt::Debug;
+use std::fs;
 use std::fs::File;
 use std::io::BufReader;
+use std::io::BufWriter;
 use std::io::Read;
+use std::io::Write;
 use std::mem;
 use std::ops::AddAssign;
 use std::ops::Index;
 
 use arrayref::array_ref;
+use byteorder::LittleEndian;
+use byteorder::WriteBytesExt;
 use log::warn;
 use memmap::Mmap;
 
+use crate::volume::Volume;
+use crate::volume::VoxelType;
+
+use super::grid::Grid;
+use super::matrix;
+use super::matrix::Matrix;
+use super::region::BoundingBox;
+use super::Transform;
+
 // This code assumes all over the place 3 dimensions
 pub const K: usize = 3;
 
+// Meta-information attribute holding the origin of the field.
+const ORIGIN_ATTRIBUTE: &str = "mercator_origin";
+
 // 2016: l now means long (64bit), i int (32bit), s short (16bit)
 kd_point!(Point3dd, f64, 3);
 kd_point!(Point3df, f32, 3);
@@ -49,7 +66,13 @@ impl From<&Point3dd> for Point3di {
     }
 }
 
-struct GISArrayData(*const f32, Mmap, usize);
+// Keeps the memory pointed to by GISArrayData alive.
+enum Storage {
+    Mapped(Mmap),
+    Owned(Vec<f32>),
+}
+
+struct GISArrayData(*const f32, Storage, usize);
 
 impl GISArrayData {
     fn load_file(basename: &str) -> Result<Self, Box<dyn Error>> {
@@ -78,7 +101,21 @@ impl GISArrayData {
         #[allow(clippy::cast_ptr_alignment)]
         let data = mmap.as_ptr() as *const _;
 
-        Ok(Self(data, mmap, count))
+        Ok(Self(data, Storage::Mapped(mmap), count))
+    }
+
+    fn from_vec(values: Vec<f32>) -> Self {
+        let data = values.as_ptr();
+        let count = values.len();
+
+        Self(data, Storage::Owned(values), count)
+    }
+
+    fn as_slice(&self) -> &[f32] {
+        match &self.1 {
+            Storage::Owned(values) => values,
+            Storage::Mapped(_) => unsafe { std::slice::from_raw_parts(self.0, self.2) },
+        }
     }
 
     fn point3dd(&self, index: usize) -> Point3dd {
@@ -118,12 +155,26 @@ enum ElementType {
     Point3Df,
 }
 
+// How the values of a field are used: SPM and DARTEL y_ fields store the
+// target positions instead of the displacements. Both give the same results
+// up to the last control points, past which the values are kept constant.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FieldMode {
+    Displacement, // Values are added to the input position
+    Absolute,     // Values are the transformed positions
+}
+
+// First and last control points along an axis, with their weights.
+type AxisWeights = (i32, i32, [f64; 2]);
+
 #[derive(Debug)]
 pub struct GISTransform {
     dimensions: Vec<usize>,
     element_type: ElementType,
     spacing: Vec<f64>, // Voxel spacing in millimeters, default is 1mm for unspecified values
+    origin: Point3dd,  // Position of the first control point, in millimeters
     flat: Vec<bool>,
+    mode: FieldMode,
     data: GISArrayData,
 }
 
@@ -173,20 +224,147 @@ impl GISTransform {
         }
 
         let data = GISArrayData::load_file(&basename)?;
+        let origin = load_origin(basename)?;
 
         Ok(Self {
             dimensions,
             element_type: ElementType::Point3Df,
             spacing,
+            origin,
             flat: vec![false, false, false],
+            mode: FieldMode::Displacement,
             data,
         })
     }
 
+    // Build a field from displacements sampled on a grid, stored as
+    // consecutive (x, y, z) triplets, with x varying fastest.
+    pub fn from_grid(grid: &Grid, displacements: Vec<f32>) -> Result<Self, Box<dyn Error>> {
+        if displacements.len() != grid.len() * K {
+            return Err(format!(
+                "Expected {} values for a {:?} grid, got {}",
+                grid.len() * K,
+                grid.dimensions,
+                displacements.len()
+            )
+            .into());
+        }
+
+        Ok(Self {
+            dimensions: grid.dimensions.to_vec(),
+            element_type: ElementType::Point3Df,
+            spacing: grid.spacing.0.to_vec(),
+            origin: grid.origin.clone(),
+            flat: vec![false, false, false],
+            mode: FieldMode::Displacement,
+            data: GISArrayData::from_vec(displacements),
+        })
+    }
+
+    // GIS files always store displacements.
+    pub fn save(&self, basename: &str) -> Result<(), Box<dyn Error>> {
+        if self.mode == FieldMode::Absolute {
+            return self.to_displacement().save(basename);
+        }
+
+        let d = &self.dimensions;
+        let s = &self.spacing;
+
+        let mut file_out = BufWriter::new(File::create(format!("{}.dim", basename))?);
+        writeln!(file_out, "{} {} {} 1", d[0], d[1], d[2])?;
+        match self.element_type {
+            ElementType::Point3Df => writeln!(file_out, "-type POINT3DF")?,
+        }
+        writeln!(file_out, "-dx {} -dy {} -dz {} -dt 1", s[0], s[1], s[2])?;
+        writeln!(file_out, "-bo DCBA")?;
+        writeln!(file_out, "-om binar")?;
+
+        let mut file_out = BufWriter::new(File::create(format!("{}.ima", basename))?);
+        for v in self.data.as_slice() {
+            file_out.write_f32::<LittleEndian>(*v)?;
+        }
+        file_out.flush()?;
+
+        save_origin(basename, &self.origin)
+    }
+
     pub fn dimensions(&self) -> &Vec<usize> {
         &self.dimensions
     }
 
+    pub fn spacing(&self) -> &Vec<f64> {
+        &self.spacing
+    }
+
+    pub fn origin(&self) -> &Point3dd {
+        &self.origin
+    }
+
+    pub fn mode(&self) -> FieldMode {
+        self.mode
+    }
+
+    // Interpret the stored values according to `mode`, without changing them.
+    pub fn with_mode(mut self, mode: FieldMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    // Same field, storing the displacements.
+    pub fn to_displacement(&self) -> Self {
+        self.converted(FieldMode::Displacement, |p| p)
+    }
+
+    // Same field, storing the transformed positions.
+    pub fn to_absolute(&self) -> Self {
+        self.converted(FieldMode::Absolute, |p| p)
+    }
+
+    // The position of each control point, given by `position` from its
+    // millimeter coordinates, is subtracted from, or added to its value.
+    // NaN values stay NaN.
+    pub(crate) fn converted<F>(&self, mode: FieldMode, position: F) -> Self
+    where
+        F: Fn(Point3dd) -> Point3dd,
+    {
+        let grid = self.grid();
+        let d = grid.dimensions;
+        let sign = match (self.mode, mode) {
+            (FieldMode::Displacement, FieldMode::Absolute) => 1.0,
+            (FieldMode::Absolute, FieldMode::Displacement) => -1.0,
+            _ => 0.0,
+        };
+
+        let mut values = Vec::with_capacity(grid.len() * K);
+        for z in 0..d[2] {
+            for y in 0..d[1] {
+                for x in 0..d[0] {
+                    let p = position(grid.position(x, y, z));
+                    let v = self.ctrl_point(x, y, z);
+                    for k in 0..K {
+                        values.push((f64::from(v[k]) + sign * p[k]) as f32);
+                    }
+                }
+            }
+        }
+
+        // The number of values matches the grid by construction.
+        GISTransform::from_grid(&grid, values)
+            .unwrap()
+            .with_mode(mode)
+    }
+
+    pub fn grid(&self) -> Grid {
+        let d = &self.dimensions;
+        let s = &self.spacing;
+
+        Grid::new(
+            self.origin.clone(),
+            Point3dd([s[0], s[1], s[2]]),
+            [d[0], d[1], d[2]],
+        )
+    }
+
     pub fn dimensions_mm(&self) -> Point3dd {
         let d = &self.dimensions;
         let s = &self.spacing;
@@ -213,16 +391,42 @@ impl GISTransform {
     pub fn point3df(&self, position: Vec<usize>) -> Point3df {
         self.data.point3df(self.index(position))
     }
+
+    // Same as `index`, without allocating the position.
+    fn offset(&self, i: i32, j: i32, k: i32) -> usize {
+        let d = &self.dimensions;
+        i as usize + d[0] * (j as usize + d[1] * k as usize)
+    }
+
+    // Same as `point3df`, without allocating the position.
+    pub(crate) fn ctrl_point(&self, i: usize, j: usize, k: usize) -> Point3df {
+        let d = &self.dimensions;
+        self.data.point3df(i + d[0] * (j + d[1] * k))
+    }
+
+    // Displacement of a control point, whatever the mode of the field.
+    pub(crate) fn ctrl_displacement(&self, i: usize, j: usize, k: usize) -> Point3df {
+        let mut v = self.ctrl_point(i, j, k);
+        if self.mode == FieldMode::Absolute {
+            for (axis, index) in [i, j, k].iter().enumerate() {
+                v.0[axis] -= (self.origin[axis] + *index as f64 * self.spacing[axis]) as f32;
+            }
+        }
+
+        v
+    }
 }
 
 impl GISTransform {
     // Translated from C++ code from:
     //  https://github.com/brainvisa/aims-free/blob/master/aimsalgo/src/aimsalgo/registration/ffd.cc#L720-L805
     fn mm_to_spline_voxel(&self, p: &Point3dd) -> Point3dd {
+        let o = &self.origin;
+
         Point3dd([
-            p[0] / self.spacing[0],
-            p[1] / self.spacing[1],
-            p[2] / self.spacing[2],
+            (p[0] - o[0]) / self.spacing[0],
+            (p[1] - o[1]) / self.spacing[1],
+            (p[2] - o[2]) / self.spacing[2],
         ])
     }
 
@@ -296,13 +500,302 @@ impl GISTransform {
         deformation
     }
 
+    // Interpolated displacement at p, in [mm].
+    pub fn displacement(&self, p: &Point3dd) -> Point3dd {
+        let mut d = self.deformation_private(p);
+        if self.mode == FieldMode::Absolute {
+            for k in 0..K {
+                d.0[k] -= p[k];
+            }
+        }
+
+        d
+    }
+
     pub fn deformation(&self, p: &Point3dd) -> Point3dd {
+        if self.mode == FieldMode::Absolute {
+            return self.deformation_private(p);
+        }
+
         let mut t = p.clone();
 
         t += self.deformation_private(&t);
 
         t
     }
+
+    // Same as `deformation` on every voxel of the grid, x varying fastest,
+    // written to `out`. The interpolation weights are computed once per
+    // axis instead of once per voxel, which gives identical results.
+    pub fn transform_grid(&self, grid: &Grid, out: &mut [Point3dd]) -> Result<(), Box<dyn Error>> {
+        if out.len() != grid.len() {
+            return Err(format!(
+                "Output buffer has {} entries, expected {}",
+                out.len(),
+                grid.len()
+            )
+            .into());
+        }
+
+        let axes = (0..K)
+            .map(|k| self.axis_weights(grid, k))
+            .collect::<Vec<_>>();
+        let nan = Point3dd([f64::NAN; K]);
+        let mut out = out.iter_mut();
+
+        for (pz, wz) in &axes[2] {
+            for (py, wy) in &axes[1] {
+                for (px, wx) in &axes[0] {
+                    let p = Point3dd([*px, *py, *pz]);
+
+                    // The size of the buffer has been checked above.
+                    *out.next().unwrap() = match (wx, wy, wz) {
+                        (Some(wx), Some(wy), Some(wz)) if self.mode == FieldMode::Absolute => {
+                            self.interpolate(wx, wy, wz)
+                        }
+                        (Some(wx), Some(wy), Some(wz)) => {
+                            let mut t = p;
+                            t += self.interpolate(wx, wy, wz);
+                            t
+                        }
+                        _ => nan.clone(),
+                    };
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Position along axis k of each grid voxel, with the first control point
+    // index and the interpolation weights, None when outside of the field.
+    fn axis_weights(&self, grid: &Grid, k: usize) -> Vec<(f64, Option<AxisWeights>)> {
+        let dim = self.dimensions[k] as i32;
+
+        (0..grid.dimensions[k])
+            .map(|i| {
+                let p = grid.origin[k] + i as f64 * grid.spacing[k];
+                let p_spline = (p - self.origin[k]) / self.spacing[k];
+
+                if !(p_spline >= 0.0 && p_spline < f64::from(dim)) {
+                    return (p, None);
+                }
+
+                let k_spline = p_spline.floor() as i32;
+                let k_up = k_spline + 1;
+
+                let weights = if self.flat[k] || k_up >= dim {
+                    (k_spline, k_spline, [1.0, 0.0])
+                } else {
+                    let b = p_spline - f64::from(k_spline);
+                    (k_spline, k_up, [1.0 - b, b])
+                };
+
+                (p, Some(weights))
+            })
+            .collect()
+    }
+
+    // Same summation order as `deformation_private`.
+    fn interpolate(
+        &self,
+        (x0, x1, bx): &AxisWeights,
+        (y0, y1, by): &AxisWeights,
+        (z0, z1, bz): &AxisWeights,
+    ) -> Point3dd {
+        let mut deformation = Point3dd([0., 0., 0.]);
+        for k in *z0..=*z1 {
+            for j in *y0..=*y1 {
+                for i in *x0..=*x1 {
+                    let mut p = self.data.point3dd(self.offset(i, j, k));
+                    p.scale(bx[(i - x0) as usize])
+                        .scale(by[(j - y0) as usize])
+                        .scale(bz[(k - z0) as usize]);
+
+                    deformation += p;
+                }
+            }
+        }
+
+        deformation
+    }
+
+    // Jacobian matrix of `deformation` at p, J[i][j] = d t_i / d p_j,
+    // using central differences over half a voxel, and one-sided ones on
+    // the borders of the field.
+    pub fn jacobian(&self, p: &Point3dd) -> Matrix {
+        let mut steps = [[0f64; K]; K];
+        for (k, row) in steps.iter_mut().enumerate() {
+            row[k] = self.spacing[k] / 2.0;
+        }
+
+        jacobian(|q| self.deformation(q), p, &steps)
+    }
+
+    // Determinant of the jacobian on every control point, greater than 1
+    // where the deformation expands space, and lower where it contracts it.
+    pub fn jacobian_map(&self) -> Volume {
+        let grid = self.grid();
+        let d = grid.dimensions;
+        let mut map = Volume::filled(grid.clone(), VoxelType::Float, 0.0);
+
+        for z in 0..d[2] {
+            for y in 0..d[1] {
+                for x in 0..d[0] {
+                    let j = self.jacobian(&grid.position(x, y, z));
+                    map.data_mut()[grid.index(x, y, z)] = matrix::determinant(&j);
+                }
+            }
+        }
+
+        map
+    }
+}
+
+impl Transform for GISTransform {
+    fn transform(&self, p: &Point3dd) -> Point3dd {
+        self.deformation(p)
+    }
+
+    fn domain(&self) -> Option<BoundingBox> {
+        let mut high = self.dimensions_mm();
+        high += self.origin.clone();
+
+        Some(BoundingBox::new(self.origin.clone(), high))
+    }
+
+    fn inverse(&self) -> Option<Box<dyn Transform>> {
+        None
+    }
+}
+
+// Jacobian matrix of `deformation` at p, from finite differences along each
+// column of `steps`. Differences are one-sided when one of the neighbours
+// deforms to NaN, and NaN when both do.
+pub(crate) fn jacobian<F: Fn(&Point3dd) -> Point3dd>(
+    deformation: F,
+    p: &Point3dd,
+    steps: &Matrix,
+) -> Matrix {
+    let center = deformation(p);
+    let mut derivatives = [[0f64; K]; K];
+
+    for j in 0..K {
+        let mut before = p.clone();
+        let mut after = p.clone();
+        for (k, row) in steps.iter().enumerate() {
+            before.0[k] -= row[j];
+            after.0[k] += row[j];
+        }
+
+        let (mut before, mut after) = (deformation(&before), deformation(&after));
+        let mut length = 2.0;
+        if before.is_nan() {
+            before = center.clone();
+            length -= 1.0;
+        }
+        if after.is_nan() {
+            after = center.clone();
+            length -= 1.0;
+        }
+
+        for (i, row) in derivatives.iter_mut().enumerate() {
+            row[j] = (after[i] - before[i]) / length;
+        }
+    }
+
+    // Derivatives along the steps to derivatives along the axes.
+    match matrix::inverse(steps) {
+        Some(inverse) => matrix::multiply(&derivatives, &inverse),
+        None => [[f64::NAN; K]; K],
+    }
+}
+
+// AIMS .dim headers have no origin, so we store it in the meta-information
+// file when it is not at 0, which happens for fields computed in memory.
+pub(crate) fn load_origin(basename: &str) -> Result<Point3dd, Box<dyn Error>> {
+    let filename = format!("{}.ima.minf", basename);
+    let mut origin = Point3dd([0.; K]);
+
+    let mut string = String::new();
+    match File::open(&filename) {
+        Ok(f) => BufReader::new(f).read_to_string(&mut string)?,
+        Err(_) => return Ok(origin),
+    };
+
+    if let Some(start) = string.find(&format!("'{}'", ORIGIN_ATTRIBUTE)) {
+        let values = &string[start..];
+        let values = match (values.find('['), values.find(']')) {
+            (Some(b), Some(e)) if b < e => &values[b + 1..e],
+            _ => return Err(format!("{}: malformed {}", filename, ORIGIN_ATTRIBUTE).into()),
+        };
+
+        for (k, v) in values.split(',').take(K).enumerate() {
+            origin.0[k] = v.trim().parse::<f64>()?;
+        }
+    }
+
+    Ok(origin)
+}
+
+// The origin is merged into an existing meta-information file, as it may
+// hold other attributes, such as the referentials written by AIMS.
+pub(crate) fn save_origin(basename: &str, origin: &Point3dd) -> Result<(), Box<dyn Error>> {
+    let minf = format!("{}.ima.minf", basename);
+    let o = origin;
+
+    let mut string = String::new();
+    let exists = match File::open(&minf) {
+        Ok(f) => {
+            BufReader::new(f).read_to_string(&mut string)?;
+            true
+        }
+        Err(_) => false,
+    };
+
+    // Remove the previous origin, along with its line if it has one.
+    if let Some(start) = string.find(&format!("'{}'", ORIGIN_ATTRIBUTE)) {
+        let end = match string[start..].find(']') {
+            Some(e) => start + e + 1,
+            None => return Err(format!("{}: malformed {}", minf, ORIGIN_ATTRIBUTE).into()),
+        };
+        let end = end
+            + string[end..]
+                .find(|c: char| c != ',' && c != ' ')
+                .unwrap_or(0);
+        let end = if string[end..].starts_with('\n') {
+            end + 1
+        } else {
+            end
+        };
+        let start = string[..start].trim_end_matches(' ').len();
+
+        string.replace_range(start..end, "");
+    }
+
+    if (0..K).any(|k| o[k] != 0.0) {
+        let entry = format!(
+            "\n    '{}' : [ {:?}, {:?}, {:?} ],",
+            ORIGIN_ATTRIBUTE, o[0], o[1], o[2]
+        );
+
+        match string.find('{') {
+            Some(i) => string.insert_str(i + 1, &entry),
+            None => string = format!("attributes = {{{}\n}}\n", entry),
+        }
+    } else if exists && string.split_whitespace().collect::<String>() == "attributes={}" {
+        // Nothing left but our own, now stale, attribute.
+        fs::remove_file(minf)?;
+        return Ok(());
+    } else if !exists {
+        return Ok(());
+    }
+
+    let mut file_out = BufWriter::new(File::create(minf)?);
+    file_out.write_all(string.as_bytes())?;
+    file_out.flush()?;
+
+    Ok(())
 }
 
 pub fn load_file(basename: &str) -> Result<GISTransform, Box<dyn Error>> {
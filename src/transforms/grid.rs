@@ -0,0 +1,63 @@
+use super::gis::Point3dd;
+use super::region::BoundingBox;
+use super::K;
+
+// Regular grid of positions, the first voxel being at `origin`, and voxel
+// (i, j, k) at origin + (i, j, k) * spacing.
+#[derive(Clone, Debug)]
+pub struct Grid {
+    pub origin: Point3dd,  // Expressed in millimeters
+    pub spacing: Point3dd, // Expressed in millimeters
+    pub dimensions: [usize; K],
+}
+
+impl Grid {
+    pub fn new(origin: Point3dd, spacing: Point3dd, dimensions: [usize; K]) -> Self {
+        Grid {
+            origin,
+            spacing,
+            dimensions,
+        }
+    }
+
+    // Smallest grid with the given spacing covering the box.
+    pub fn covering(bounds: &BoundingBox, spacing: Point3dd) -> Self {
+        let mut dimensions = [0; K];
+        for k in 0..K {
+            dimensions[k] = ((bounds.high[k] - bounds.low[k]) / spacing[k]).floor() as usize + 1;
+        }
+
+        Self::new(bounds.low.clone(), spacing, dimensions)
+    }
+
+    pub fn len(&self) -> usize {
+        self.dimensions.iter().product()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Linear index of voxel (i, j, k), x varying fastest.
+    pub fn index(&self, i: usize, j: usize, k: usize) -> usize {
+        i + self.dimensions[0] * (j + self.dimensions[1] * k)
+    }
+
+    pub fn position(&self, i: usize, j: usize, k: usize) -> Point3dd {
+        let mut p = self.origin.clone();
+        for (k, v) in [i, j, k].iter().enumerate() {
+            p.0[k] += *v as f64 * self.spacing[k];
+        }
+
+        p
+    }
+
+    // Box spanned by the voxel positions, not their extent.
+    pub fn bounding_box(&self) -> BoundingBox {
+        let d = &self.dimensions;
+        BoundingBox::new(
+            self.origin.clone(),
+            self.position(d[0].max(1) - 1, d[1].max(1) - 1, d[2].max(1) - 1),
+        )
+    }
+}
@@ -0,0 +1,215 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::Read;
+
+use super::affine::AffineTransform;
+use super::chain::TransformChain;
+use super::convention::Convention;
+use super::gis::Point3dd;
+use super::matrix;
+use super::matrix::Matrix;
+use super::Transform;
+use super::K;
+
+// Transform as listed in the file, with its name stripped of the precision
+// and dimension suffixes, such as _double_3_3.
+struct Entry {
+    name: String,
+    parameters: Vec<f64>,
+    fixed: Vec<f64>,
+}
+
+fn parse_values(filename: &str, line: usize, value: &str) -> Result<Vec<f64>, Box<dyn Error>> {
+    match value
+        .split_whitespace()
+        .map(|v| v.parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(values) => Ok(values),
+        Err(e) => Err(format!("{}:{}: invalid values '{}': {}", filename, line, value, e).into()),
+    }
+}
+
+fn read_entries(filename: &str) -> Result<Vec<Entry>, Box<dyn Error>> {
+    let mut file_in = BufReader::new(File::open(filename)?);
+
+    let mut string = String::new();
+    file_in.read_to_string(&mut string)?;
+
+    let mut entries: Vec<Entry> = vec![];
+    for (n, line) in string.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (key, value) = match line.find(':') {
+            Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+            None => return Err(format!("{}:{}: invalid line '{}'", filename, n + 1, line).into()),
+        };
+
+        if key == "Transform" {
+            entries.push(Entry {
+                name: value.split('_').next().unwrap_or(value).to_string(),
+                parameters: vec![],
+                fixed: vec![],
+            });
+            continue;
+        }
+
+        let entry = match entries.last_mut() {
+            Some(e) => e,
+            None => {
+                return Err(format!("{}:{}: {} before any transform", filename, n + 1, key).into())
+            }
+        };
+        match key {
+            "Parameters" => entry.parameters = parse_values(filename, n + 1, value)?,
+            "FixedParameters" => entry.fixed = parse_values(filename, n + 1, value)?,
+            _ => (),
+        }
+    }
+
+    Ok(entries)
+}
+
+// Rotation of Euler3DTransform, around z, x and then y by default, or
+// around z, y and then x when `zyx` is set, the angles being in radians.
+fn euler_matrix(angles: &[f64], zyx: bool) -> Matrix {
+    let (sx, cx) = angles[0].sin_cos();
+    let (sy, cy) = angles[1].sin_cos();
+    let (sz, cz) = angles[2].sin_cos();
+
+    let rx = [[1.0, 0.0, 0.0], [0.0, cx, -sx], [0.0, sx, cx]];
+    let ry = [[cy, 0.0, sy], [0.0, 1.0, 0.0], [-sy, 0.0, cy]];
+    let rz = [[cz, -sz, 0.0], [sz, cz, 0.0], [0.0, 0.0, 1.0]];
+
+    if zyx {
+        matrix::multiply(&rz, &matrix::multiply(&ry, &rx))
+    } else {
+        matrix::multiply(&rz, &matrix::multiply(&rx, &ry))
+    }
+}
+
+// ITK maps x to M (x - c) + t + c, c being the center given by the fixed
+// parameters, that is an offset of t + c - M c.
+fn centered(matrix: Matrix, translation: &[f64], center: &[f64]) -> AffineTransform {
+    let mc = matrix::apply(&matrix, &[center[0], center[1], center[2]]);
+    let mut offsets = [0f64; K];
+    for k in 0..K {
+        offsets[k] = translation[k] + center[k] - mc[k];
+    }
+
+    AffineTransform::new(matrix, offsets)
+}
+
+fn affine(filename: &str, entry: &Entry) -> Result<AffineTransform, Box<dyn Error>> {
+    let (p, f) = (&entry.parameters, &entry.fixed);
+    let center = if f.len() >= K { &f[..K] } else { &[0.0; K][..] };
+
+    let expected = match entry.name.as_str() {
+        "AffineTransform" | "MatrixOffsetTransformBase" => K * K + K,
+        "Euler3DTransform" => 2 * K,
+        "TranslationTransform" => K,
+        name => return Err(format!("{}: unsupported transform {}", filename, name).into()),
+    };
+    if p.len() != expected {
+        return Err(format!(
+            "{}: expected {} parameters for {}, got {}",
+            filename,
+            expected,
+            entry.name,
+            p.len()
+        )
+        .into());
+    }
+
+    let transform = match entry.name.as_str() {
+        "Euler3DTransform" => {
+            let zyx = f.get(K).is_some_and(|&v| v != 0.0);
+            centered(euler_matrix(&p[..K], zyx), &p[K..], center)
+        }
+        "TranslationTransform" => AffineTransform::new(matrix::identity(), [p[0], p[1], p[2]]),
+        _ => {
+            // The matrix is stored row by row, followed by the translation.
+            let mut m = [[0f64; K]; K];
+            for (r, row) in m.iter_mut().enumerate() {
+                row.copy_from_slice(&p[r * K..(r + 1) * K]);
+            }
+            centered(m, &p[K * K..], center)
+        }
+    };
+
+    Ok(transform)
+}
+
+// Affine transforms of the file, in the order they are applied. Those of a
+// composite transform are listed after it, the last one being applied first.
+fn read_transforms(filename: &str) -> Result<Vec<AffineTransform>, Box<dyn Error>> {
+    let entries = read_entries(filename)?;
+
+    let entries = match entries.first() {
+        None => return Err(format!("{}: no transform found", filename).into()),
+        Some(e) if e.name == "CompositeTransform" => entries.iter().skip(1).rev().collect(),
+        Some(_) if entries.len() > 1 => {
+            return Err(format!(
+                "{}: expected a single transform, or a composite one, got {}",
+                filename,
+                entries.len()
+            )
+            .into())
+        }
+        Some(_) => entries.iter().collect::<Vec<_>>(),
+    };
+
+    entries.iter().map(|e| affine(filename, e)).collect()
+}
+
+// Express a transform of LPS coordinates in `convention`.
+fn converted(transform: &AffineTransform, convention: Convention) -> AffineTransform {
+    let change = Convention::Lps.change(convention);
+    let f = change.matrix();
+
+    let matrix = matrix::multiply(f, &matrix::multiply(transform.matrix(), f));
+    let offsets = change.transform(&Point3dd(*transform.offsets()));
+
+    AffineTransform::new(matrix, offsets.0)
+}
+
+// Load an ITK text transform, .tfm or .txt, as a chain of affine transforms.
+// As in the file, the transforms map LPS coordinates of the fixed image to
+// LPS coordinates of the moving one.
+pub fn load_file(filename: &str) -> Result<TransformChain, Box<dyn Error>> {
+    load_file_with(filename, Convention::Lps)
+}
+
+// Same as `load_file`, the transforms being moved to `convention`.
+pub fn load_file_with(
+    filename: &str,
+    convention: Convention,
+) -> Result<TransformChain, Box<dyn Error>> {
+    let mut chain = TransformChain::new();
+
+    for t in read_transforms(filename)? {
+        chain.push(Box::new(converted(&t, convention)), false)?;
+    }
+
+    Ok(chain)
+}
+
+// Load the transforms of the file, composed into a single one.
+pub fn load_affine(
+    filename: &str,
+    convention: Convention,
+) -> Result<AffineTransform, Box<dyn Error>> {
+    let mut result = AffineTransform::identity();
+
+    for t in read_transforms(filename)? {
+        let matrix = matrix::multiply(t.matrix(), result.matrix());
+        let offsets = t.transform(&Point3dd(*result.offsets()));
+        result = AffineTransform::new(matrix, offsets.0);
+    }
+
+    Ok(converted(&result, convention))
+}
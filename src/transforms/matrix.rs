@@ -0,0 +1,120 @@
+// Small dense matrix helpers, tied to the K dimensions used everywhere else.
+#![allow(clippy::needless_range_loop)]
+
+use super::K;
+
+pub type Matrix = [[f64; K]; K];
+
+pub fn identity() -> Matrix {
+    let mut m = [[0.; K]; K];
+    for (i, row) in m.iter_mut().enumerate() {
+        row[i] = 1.0;
+    }
+
+    m
+}
+
+pub fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut m = [[0.; K]; K];
+    for i in 0..K {
+        for j in 0..K {
+            for k in 0..K {
+                m[i][j] += a[i][k] * b[k][j];
+            }
+        }
+    }
+
+    m
+}
+
+pub fn apply(a: &Matrix, v: &[f64; K]) -> [f64; K] {
+    let mut r = [0.; K];
+    for i in 0..K {
+        for j in 0..K {
+            r[i] += a[i][j] * v[j];
+        }
+    }
+
+    r
+}
+
+pub fn transpose(a: &Matrix) -> Matrix {
+    let mut m = [[0.; K]; K];
+    for i in 0..K {
+        for j in 0..K {
+            m[i][j] = a[j][i];
+        }
+    }
+
+    m
+}
+
+pub fn determinant(a: &Matrix) -> f64 {
+    a[0][0] * (a[1][1] * a[2][2] - a[1][2] * a[2][1])
+        - a[0][1] * (a[1][0] * a[2][2] - a[1][2] * a[2][0])
+        + a[0][2] * (a[1][0] * a[2][1] - a[1][1] * a[2][0])
+}
+
+pub fn inverse(a: &Matrix) -> Option<Matrix> {
+    let det = determinant(a);
+    if det == 0.0 || !det.is_finite() {
+        return None;
+    }
+
+    // Transposed matrix of cofactors, divided by the determinant.
+    let mut m = [[0.; K]; K];
+    for i in 0..K {
+        for j in 0..K {
+            let (r0, r1) = ((j + 1) % K, (j + 2) % K);
+            let (c0, c1) = ((i + 1) % K, (i + 2) % K);
+            m[i][j] = (a[r0][c0] * a[r1][c1] - a[r0][c1] * a[r1][c0]) / det;
+        }
+    }
+
+    Some(m)
+}
+
+// Eigen decomposition of a symmetric matrix, using the cyclic Jacobi method.
+// Returns the eigenvalues, and the matching unit eigenvectors as columns.
+pub fn symmetric_eigen(a: &Matrix) -> ([f64; K], Matrix) {
+    let mut m = *a;
+    let mut v = identity();
+
+    for _ in 0..50 {
+        let off = m[0][1].powi(2) + m[0][2].powi(2) + m[1][2].powi(2);
+        if off < 1e-30 {
+            break;
+        }
+
+        for p in 0..K - 1 {
+            for q in (p + 1)..K {
+                if m[p][q] == 0.0 {
+                    continue;
+                }
+
+                let theta = (m[q][q] - m[p][p]) / (2.0 * m[p][q]);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+                let c = 1.0 / (t * t + 1.0).sqrt();
+                let s = t * c;
+
+                for k in 0..K {
+                    let (mkp, mkq) = (m[k][p], m[k][q]);
+                    m[k][p] = c * mkp - s * mkq;
+                    m[k][q] = s * mkp + c * mkq;
+                }
+                for k in 0..K {
+                    let (mpk, mqk) = (m[p][k], m[q][k]);
+                    m[p][k] = c * mpk - s * mqk;
+                    m[q][k] = s * mpk + c * mqk;
+                }
+                for row in v.iter_mut() {
+                    let (vkp, vkq) = (row[p], row[q]);
+                    row[p] = c * vkp - s * vkq;
+                    row[q] = s * vkp + c * vkq;
+                }
+            }
+        }
+    }
+
+    ([m[0][0], m[1][1], m[2][2]], v)
+}
@@ -0,0 +1,305 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+
+use byteorder::BigEndian;
+use byteorder::LittleEndian;
+use flate2::read::ZlibDecoder;
+
+use crate::volume::VoxelType;
+
+use super::affine::AffineTransform;
+use super::convention::Convention;
+use super::gis::FieldMode;
+use super::gis::GISTransform;
+use super::gis::Point3dd;
+use super::grid::Grid;
+use super::oriented::voxel_to_world;
+use super::oriented::OrientedField;
+use super::K;
+
+fn voxel_type(name: &str) -> Option<VoxelType> {
+    match name {
+        "MET_UCHAR" => Some(VoxelType::U8),
+        "MET_CHAR" => Some(VoxelType::S8),
+        "MET_USHORT" => Some(VoxelType::U16),
+        "MET_SHORT" => Some(VoxelType::S16),
+        "MET_UINT" => Some(VoxelType::U32),
+        "MET_INT" => Some(VoxelType::S32),
+        "MET_FLOAT" => Some(VoxelType::Float),
+        "MET_DOUBLE" => Some(VoxelType::Double),
+        _ => None,
+    }
+}
+
+fn parse_values(filename: &str, key: &str, value: &str) -> Result<Vec<f64>, Box<dyn Error>> {
+    match value
+        .split_whitespace()
+        .map(|v| v.parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(values) => Ok(values),
+        Err(e) => Err(format!("{}: invalid {} '{}': {}", filename, key, value, e).into()),
+    }
+}
+
+// Load a vector image of 3 channels, either a .mha file, or a .mhd header
+// with its data in a separate file. As written by ITK, both the world
+// coordinates and the displacements of the file are expected to be in LPS,
+// they are converted to RAS, as for NIfTI fields.
+pub fn load_file(filename: &str) -> Result<OrientedField, Box<dyn Error>> {
+    load_file_with(filename, Convention::Ras)
+}
+
+// Same as `load_file`, the field being converted to `convention`.
+pub fn load_file_with(
+    filename: &str,
+    convention: Convention,
+) -> Result<OrientedField, Box<dyn Error>> {
+    let mut bytes = vec![];
+    BufReader::new(File::open(filename)?).read_to_end(&mut bytes)?;
+
+    let mut dimensions = vec![];
+    let mut spacing = vec![1.0; K];
+    let mut offset = vec![0.0; K];
+    let mut directions = vec![1., 0., 0., 0., 1., 0., 0., 0., 1.];
+    let mut channels = 1;
+    let mut voxel = None;
+    let mut big_endian = false;
+    let mut compressed = false;
+    let mut header_size = 0i64;
+    let mut data_file = None;
+    let mut position = 0;
+
+    // The header ends with the name of the data file, which is LOCAL when
+    // the data follows the header.
+    while data_file.is_none() && position < bytes.len() {
+        let end = bytes[position..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(bytes.len(), |e| position + e + 1);
+        let line = String::from_utf8_lossy(&bytes[position..end]).to_string();
+        position = end;
+
+        let (key, value) = match line.find('=') {
+            Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+            None => continue,
+        };
+
+        match key {
+            "NDims" if value != "3" => {
+                return Err(format!("{}: only 3D images are supported", filename).into())
+            }
+            "DimSize" => {
+                dimensions = parse_values(filename, key, value)?
+                    .iter()
+                    .map(|&d| d as usize)
+                    .collect()
+            }
+            "ElementSpacing" => spacing = parse_values(filename, key, value)?,
+            "Offset" | "Origin" | "Position" => offset = parse_values(filename, key, value)?,
+            "TransformMatrix" | "Orientation" | "Rotation" => {
+                directions = parse_values(filename, key, value)?
+            }
+            "ElementNumberOfChannels" => channels = value.parse::<usize>()?,
+            "ElementType" => voxel = voxel_type(value),
+            "BinaryDataByteOrderMSB" | "ElementByteOrderMSB" => big_endian = value == "True",
+            "CompressedData" => compressed = value == "True",
+            "HeaderSize" => header_size = value.parse::<i64>()?,
+            "ElementDataFile" => data_file = Some(value.to_string()),
+            _ => (),
+        }
+    }
+
+    let voxel_type = match voxel {
+        Some(t) => t,
+        None => return Err(format!("{}: missing or unsupported ElementType", filename).into()),
+    };
+    if dimensions.len() != K || spacing.len() != K || offset.len() != K || directions.len() != K * K
+    {
+        return Err(format!("{}: invalid geometry", filename).into());
+    }
+    if channels != K {
+        return Err(format!(
+            "{}: expected {} channels per voxel, got {}",
+            filename, K, channels
+        )
+        .into());
+    }
+
+    let mut data = match data_file.as_deref() {
+        Some("LOCAL") => bytes.split_off(position),
+        Some("LIST") | None => {
+            return Err(format!("{}: missing or unsupported ElementDataFile", filename).into())
+        }
+        Some(name) => {
+            let path = Path::new(filename).with_file_name(name);
+            let mut data = vec![];
+            BufReader::new(File::open(path)?).read_to_end(&mut data)?;
+            data
+        }
+    };
+
+    let grid = Grid::new(
+        Point3dd([0.; K]),
+        Point3dd([1.; K]),
+        [dimensions[0], dimensions[1], dimensions[2]],
+    );
+    let count = grid.len() * K;
+
+    if compressed {
+        let mut decoded = vec![];
+        ZlibDecoder::new(&data[..]).read_to_end(&mut decoded)?;
+        data = decoded;
+    }
+
+    // A header size of -1 means the data is at the end of the file.
+    let length = count * voxel_type.size();
+    let start = if header_size < 0 {
+        data.len().saturating_sub(length)
+    } else if compressed {
+        0
+    } else {
+        header_size as usize
+    };
+    if start + length > data.len() {
+        return Err(format!(
+            "{}: expected {} bytes of data, got {}",
+            filename,
+            length,
+            data.len().saturating_sub(start)
+        )
+        .into());
+    }
+
+    // Channels are interleaved, as in GIS fields.
+    let mut reader = &data[start..];
+    let mut displacements = Vec::with_capacity(count);
+    for _ in 0..count {
+        let v = if big_endian {
+            voxel_type.read::<BigEndian, _>(&mut reader)?
+        } else {
+            voxel_type.read::<LittleEndian, _>(&mut reader)?
+        };
+        displacements.push(v as f32);
+    }
+
+    // Each group of 3 values is the direction of an axis.
+    let mut matrix = [[0f64; K]; K];
+    for (r, row) in matrix.iter_mut().enumerate() {
+        for (c, v) in row.iter_mut().enumerate() {
+            *v = directions[c * K + r] * spacing[c];
+        }
+    }
+    let to_world = AffineTransform::new(matrix, [offset[0], offset[1], offset[2]]);
+
+    let field = OrientedField::new(GISTransform::from_grid(&grid, displacements)?, to_world)?;
+
+    Ok(field.change_convention(Convention::Lps, convention))
+}
+
+// Save the displacements of the field as 32 bits floats, in a single file
+// for .mha names, and otherwise with the data in a .raw file next to the
+// header. `to_world` maps the millimeter coordinates of the field to the
+// world coordinates of the file.
+fn write_field(
+    filename: &str,
+    field: &GISTransform,
+    to_world: &AffineTransform,
+) -> Result<(), Box<dyn Error>> {
+    let d = field.dimensions();
+    let to_world = voxel_to_world(&field.grid(), to_world);
+    let m = to_world.matrix();
+    let t = to_world.offsets();
+
+    let mut spacing = [0f64; K];
+    for (c, s) in spacing.iter_mut().enumerate() {
+        *s = (0..K).map(|r| m[r][c] * m[r][c]).sum::<f64>().sqrt();
+    }
+    let directions = (0..K)
+        .flat_map(|c| (0..K).map(move |r| m[r][c] / spacing[c]))
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>();
+
+    let (data_file, raw) = if filename.ends_with(".mha") {
+        ("LOCAL".to_string(), None)
+    } else {
+        let raw = Path::new(filename).with_extension("raw");
+        let name = match raw.file_name() {
+            Some(name) => name.to_string_lossy().to_string(),
+            None => return Err(format!("{}: invalid file name", filename).into()),
+        };
+        (name, Some(raw))
+    };
+
+    let mut file_out = BufWriter::new(File::create(filename)?);
+    writeln!(file_out, "ObjectType = Image")?;
+    writeln!(file_out, "NDims = 3")?;
+    writeln!(file_out, "BinaryData = True")?;
+    writeln!(file_out, "BinaryDataByteOrderMSB = False")?;
+    writeln!(file_out, "CompressedData = False")?;
+    writeln!(file_out, "TransformMatrix = {}", directions.join(" "))?;
+    writeln!(file_out, "Offset = {} {} {}", t[0], t[1], t[2])?;
+    writeln!(
+        file_out,
+        "ElementSpacing = {} {} {}",
+        spacing[0], spacing[1], spacing[2]
+    )?;
+    writeln!(file_out, "DimSize = {} {} {}", d[0], d[1], d[2])?;
+    writeln!(file_out, "ElementNumberOfChannels = {}", K)?;
+    writeln!(file_out, "ElementType = MET_FLOAT")?;
+    writeln!(file_out, "ElementDataFile = {}", data_file)?;
+
+    if let Some(raw) = raw {
+        file_out.flush()?;
+        file_out = BufWriter::new(File::create(raw)?);
+    }
+
+    for z in 0..d[2] {
+        for y in 0..d[1] {
+            for x in 0..d[0] {
+                let p = field.ctrl_point(x, y, z);
+                for k in 0..K {
+                    VoxelType::Float.write::<LittleEndian, _>(&mut file_out, f64::from(p[k]))?;
+                }
+            }
+        }
+    }
+    file_out.flush()?;
+
+    Ok(())
+}
+
+// The world coordinates of the field are expected to be in RAS, as for
+// NIfTI fields, and are converted to the LPS of ITK.
+pub fn save_file(filename: &str, field: &OrientedField) -> Result<(), Box<dyn Error>> {
+    save_file_with(filename, field, Convention::Ras)
+}
+
+// Same as `save_file`, for a field in `convention`. Absolute fields are
+// converted, as ITK only handles displacements.
+pub fn save_file_with(
+    filename: &str,
+    field: &OrientedField,
+    convention: Convention,
+) -> Result<(), Box<dyn Error>> {
+    let field = match field.field().mode() {
+        FieldMode::Displacement => field.change_convention(convention, Convention::Lps),
+        FieldMode::Absolute => field
+            .to_displacement()
+            .change_convention(convention, Convention::Lps),
+    };
+
+    write_field(filename, field.field(), field.to_world())
+}
+
+// Save a field, its millimeter coordinates being RAS world coordinates.
+pub fn save_gis(filename: &str, field: &GISTransform) -> Result<(), Box<dyn Error>> {
+    let field = OrientedField::new(field.to_displacement(), AffineTransform::identity())?;
+
+    save_file(filename, &field)
+}
@@ -2,13 +2,44 @@
 mod point;
 
 pub mod affine;
+pub mod bounds;
+pub mod chain;
+pub mod compose;
+pub mod convention;
+mod crop;
+pub mod flirt;
+pub mod fnirt;
 pub mod gis;
+pub mod grid;
+pub mod itk;
+mod matrix;
+pub mod metaimage;
+pub mod nifti;
+pub mod nrrd;
+pub mod oriented;
+pub mod pyramid;
+pub mod region;
+pub mod registry;
+mod resample;
+mod velocity;
 
+use std::fmt::Debug;
+
+use gis::Point3dd;
 use gis::K;
+use region::BoundingBox;
+
+// Common interface of everything mapping points from one space to another.
+// Points which cannot be mapped are returned as NaN.
+pub trait Transform: Debug {
+    fn transform(&self, p: &Point3dd) -> Point3dd;
+
+    fn transform_batch(&self, points: &[Point3dd]) -> Vec<Point3dd> {
+        points.iter().map(|p| self.transform(p)).collect()
+    }
+
+    // Source region on which the transform is defined, None when unbounded.
+    fn domain(&self) -> Option<BoundingBox>;
 
-/*
-enum Transform<'t> {
-    Affine(AffineTransform),
-    DeformationMap(GISDim, GISData<'t>),
+    fn inverse(&self) -> Option<Box<dyn Transform>>;
 }
-*/
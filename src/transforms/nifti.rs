@@ -0,0 +1,663 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+
+use byteorder::BigEndian;
+use byteorder::ByteOrder;
+use byteorder::LittleEndian;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::volume::Volume;
+use crate::volume::VoxelType;
+
+use super::affine::AffineTransform;
+use super::convention::Convention;
+use super::gis::FieldMode;
+use super::gis::GISTransform;
+use super::gis::Point3dd;
+use super::grid::Grid;
+use super::matrix;
+use super::matrix::Matrix;
+use super::oriented::voxel_to_world;
+use super::oriented::OrientedField;
+use super::K;
+
+// Intent codes of vector images.
+pub const INTENT_DISPLACEMENT: i32 = 1006;
+pub const INTENT_VECTOR: i32 = 1007;
+
+const NIFTI1_SIZE: i32 = 348;
+const NIFTI2_SIZE: i32 = 540;
+
+// Data follows the header and an empty extension block.
+const NIFTI1_OFFSET: usize = 352;
+
+// What we need of NIfTI-1 and NIfTI-2 headers, which share the same fields
+// with different widths and offsets.
+#[derive(Debug)]
+pub(crate) struct Header {
+    pub dimensions: Vec<usize>, // dim[1..=dim[0]]
+    pub voxel_type: VoxelType,
+    pub intent: i32,
+    pub parameters: [f64; 3], // intent_p1 to intent_p3
+    pub pixdim: [f64; 8],
+    pub to_world: AffineTransform, // Voxel indices to world coordinates, in millimeters
+    vox_offset: usize,
+    slope: f64,
+    intercept: f64,
+    big_endian: bool,
+}
+
+fn voxel_type(datatype: i16) -> Option<VoxelType> {
+    match datatype {
+        2 => Some(VoxelType::U8),
+        4 => Some(VoxelType::S16),
+        8 => Some(VoxelType::S32),
+        16 => Some(VoxelType::Float),
+        64 => Some(VoxelType::Double),
+        256 => Some(VoxelType::S8),
+        512 => Some(VoxelType::U16),
+        768 => Some(VoxelType::U32),
+        _ => None,
+    }
+}
+
+fn datatype(voxel_type: VoxelType) -> i16 {
+    match voxel_type {
+        VoxelType::U8 => 2,
+        VoxelType::S16 => 4,
+        VoxelType::S32 => 8,
+        VoxelType::Float => 16,
+        VoxelType::Double => 64,
+        VoxelType::S8 => 256,
+        VoxelType::U16 => 512,
+        VoxelType::U32 => 768,
+    }
+}
+
+// Rotation part of the qform, from the quaternion (b, c, d), with a >= 0.
+fn quaternion_matrix(b: f64, c: f64, d: f64) -> [[f64; K]; K] {
+    let (mut a, mut b, mut c, mut d) = (1.0 - (b * b + c * c + d * d), b, c, d);
+    if a < 1e-7 {
+        // Rotation of 180 degrees, renormalise (b, c, d).
+        let n = (b * b + c * c + d * d).sqrt();
+        b /= n;
+        c /= n;
+        d /= n;
+        a = 0.0;
+    } else {
+        a = a.sqrt();
+    }
+
+    [
+        [
+            a * a + b * b - c * c - d * d,
+            2.0 * (b * c - a * d),
+            2.0 * (b * d + a * c),
+        ],
+        [
+            2.0 * (b * c + a * d),
+            a * a + c * c - b * b - d * d,
+            2.0 * (c * d - a * b),
+        ],
+        [
+            2.0 * (b * d - a * c),
+            2.0 * (c * d + a * b),
+            a * a + d * d - c * c - b * b,
+        ],
+    ]
+}
+
+// Quaternion (b, c, d), with a >= 0, of a rotation matrix.
+fn matrix_quaternion(r: &Matrix) -> [f64; K] {
+    let trace = r[0][0] + r[1][1] + r[2][2] + 1.0;
+    let (a, b, c, d);
+
+    if trace > 0.5 {
+        a = 0.5 * trace.sqrt();
+        b = 0.25 * (r[2][1] - r[1][2]) / a;
+        c = 0.25 * (r[0][2] - r[2][0]) / a;
+        d = 0.25 * (r[1][0] - r[0][1]) / a;
+    } else {
+        let xd = 1.0 + r[0][0] - (r[1][1] + r[2][2]);
+        let yd = 1.0 + r[1][1] - (r[0][0] + r[2][2]);
+        let zd = 1.0 + r[2][2] - (r[0][0] + r[1][1]);
+
+        if xd > 1.0 {
+            b = 0.5 * xd.sqrt();
+            c = 0.25 * (r[0][1] + r[1][0]) / b;
+            d = 0.25 * (r[0][2] + r[2][0]) / b;
+            a = 0.25 * (r[2][1] - r[1][2]) / b;
+        } else if yd > 1.0 {
+            c = 0.5 * yd.sqrt();
+            b = 0.25 * (r[0][1] + r[1][0]) / c;
+            d = 0.25 * (r[1][2] + r[2][1]) / c;
+            a = 0.25 * (r[0][2] - r[2][0]) / c;
+        } else {
+            d = 0.5 * zd.sqrt();
+            b = 0.25 * (r[0][2] + r[2][0]) / d;
+            c = 0.25 * (r[1][2] + r[2][1]) / d;
+            a = 0.25 * (r[1][0] - r[0][1]) / d;
+        }
+    }
+
+    if a < 0.0 {
+        [-b, -c, -d]
+    } else {
+        [b, c, d]
+    }
+}
+
+fn parse_header<B: ByteOrder>(bytes: &[u8], big_endian: bool) -> Result<Header, Box<dyn Error>> {
+    let version2 = B::read_i32(bytes) == NIFTI2_SIZE;
+    let size = if version2 { NIFTI2_SIZE } else { NIFTI1_SIZE } as usize;
+    if bytes.len() < size {
+        return Err("Truncated NIfTI header".into());
+    }
+
+    // Fields of NIfTI-2 are 64 bits wide, integers of NIfTI-1 mostly 16 bits.
+    let float = |offset: usize| -> f64 {
+        if version2 {
+            B::read_f64(&bytes[offset..])
+        } else {
+            f64::from(B::read_f32(&bytes[offset..]))
+        }
+    };
+    let width = if version2 { 8 } else { 4 };
+
+    let magic = if version2 {
+        &bytes[4..7]
+    } else {
+        &bytes[344..347]
+    };
+    match magic {
+        b"n+1" | b"n+2" => (),
+        b"ni1" | b"ni2" => {
+            return Err("Separate NIfTI header and data files are not supported".into())
+        }
+        _ => return Err("Invalid NIfTI magic string".into()),
+    }
+
+    let mut dim = [0i64; 8];
+    for (i, d) in dim.iter_mut().enumerate() {
+        *d = if version2 {
+            B::read_i64(&bytes[16 + 8 * i..])
+        } else {
+            i64::from(B::read_i16(&bytes[40 + 2 * i..]))
+        };
+    }
+    if dim[0] < 1 || dim[0] > 7 || dim[1..=dim[0] as usize].iter().any(|&d| d < 1) {
+        return Err(format!("Invalid NIfTI dimensions {:?}", dim).into());
+    }
+    let dimensions = dim[1..=dim[0] as usize]
+        .iter()
+        .map(|&d| d as usize)
+        .collect::<Vec<_>>();
+
+    let pixdim_offset = if version2 { 104 } else { 76 };
+    let mut pixdim = [0f64; 8];
+    for (i, p) in pixdim.iter_mut().enumerate() {
+        *p = float(pixdim_offset + width * i);
+    }
+
+    let (datatype, intent) = if version2 {
+        (B::read_i16(&bytes[12..]), B::read_i32(&bytes[504..]))
+    } else {
+        (
+            B::read_i16(&bytes[70..]),
+            i32::from(B::read_i16(&bytes[68..])),
+        )
+    };
+    let parameters_offset = if version2 { 80 } else { 56 };
+    let mut parameters = [0f64; 3];
+    for (i, p) in parameters.iter_mut().enumerate() {
+        *p = float(parameters_offset + width * i);
+    }
+
+    let voxel_type = match voxel_type(datatype) {
+        Some(t) => t,
+        None => return Err(format!("Unsupported NIfTI datatype {}", datatype).into()),
+    };
+
+    let vox_offset = if version2 {
+        B::read_i64(&bytes[168..]) as usize
+    } else {
+        float(108) as usize
+    };
+    let (slope, intercept) = if version2 {
+        (float(176), float(184))
+    } else {
+        (float(112), float(116))
+    };
+
+    let (qform, sform) = if version2 {
+        (B::read_i32(&bytes[344..]), B::read_i32(&bytes[348..]))
+    } else {
+        (
+            i32::from(B::read_i16(&bytes[252..])),
+            i32::from(B::read_i16(&bytes[254..])),
+        )
+    };
+
+    // Orientation from the sform when set, then the qform, and otherwise
+    // only the voxel sizes, as recommended by the standard.
+    let to_world = if sform > 0 {
+        let srow = if version2 { 400 } else { 280 };
+        let mut matrix = [[0f64; K]; K];
+        let mut offsets = [0f64; K];
+        for (r, row) in matrix.iter_mut().enumerate() {
+            for (c, v) in row.iter_mut().enumerate() {
+                *v = float(srow + width * (4 * r + c));
+            }
+            offsets[r] = float(srow + width * (4 * r + 3));
+        }
+
+        AffineTransform::new(matrix, offsets)
+    } else if qform > 0 {
+        let quatern = if version2 { 352 } else { 256 };
+        let q = (0..6)
+            .map(|i| float(quatern + width * i))
+            .collect::<Vec<_>>();
+        let qfac = if pixdim[0] < 0.0 { -1.0 } else { 1.0 };
+
+        let mut matrix = quaternion_matrix(q[0], q[1], q[2]);
+        for row in matrix.iter_mut() {
+            for (c, v) in row.iter_mut().enumerate() {
+                *v *= pixdim[c + 1];
+            }
+            row[2] *= qfac;
+        }
+
+        AffineTransform::new(matrix, [q[3], q[4], q[5]])
+    } else {
+        let mut matrix = [[0f64; K]; K];
+        for (k, row) in matrix.iter_mut().enumerate() {
+            row[k] = if pixdim[k + 1] > 0.0 {
+                pixdim[k + 1]
+            } else {
+                1.0
+            };
+        }
+
+        AffineTransform::new(matrix, [0.; K])
+    };
+
+    Ok(Header {
+        dimensions,
+        voxel_type,
+        intent,
+        parameters,
+        pixdim,
+        to_world,
+        vox_offset,
+        slope,
+        intercept,
+        big_endian,
+    })
+}
+
+// Open a .nii or .nii.gz file and read its header, the reader being left
+// at the start of the data.
+fn open(filename: &str) -> Result<(Header, Box<dyn Read>), Box<dyn Error>> {
+    let mut file_in = BufReader::new(File::open(filename)?);
+    let mut reader: Box<dyn Read> = if file_in.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+        Box::new(BufReader::new(GzDecoder::new(file_in)))
+    } else {
+        Box::new(file_in)
+    };
+
+    // The size of the header tells us about the version, and the byte order.
+    let mut bytes = vec![0u8; 4];
+    if reader.read_exact(&mut bytes).is_err() {
+        return Err(format!("{}: not a NIfTI file", filename).into());
+    }
+    let size = LittleEndian::read_i32(&bytes);
+    let little_endian = size == NIFTI1_SIZE || size == NIFTI2_SIZE;
+    let version2 = size == NIFTI2_SIZE || BigEndian::read_i32(&bytes) == NIFTI2_SIZE;
+
+    bytes.resize(if version2 { NIFTI2_SIZE } else { NIFTI1_SIZE } as usize, 0);
+    if reader.read_exact(&mut bytes[4..]).is_err() {
+        return Err(format!("{}: Truncated NIfTI header", filename).into());
+    }
+
+    let header = if little_endian {
+        parse_header::<LittleEndian>(&bytes, false)
+    } else {
+        parse_header::<BigEndian>(&bytes, true)
+    };
+    let header = match header {
+        Ok(h) => h,
+        Err(e) => return Err(format!("{}: {}", filename, e).into()),
+    };
+
+    // Skip the extensions, if any.
+    let skip = match header.vox_offset.checked_sub(bytes.len()) {
+        Some(v) => v as u64,
+        None => {
+            return Err(format!(
+                "{}: data offset {} within the header",
+                filename, header.vox_offset
+            )
+            .into())
+        }
+    };
+    if std::io::copy(&mut reader.by_ref().take(skip), &mut std::io::sink())? != skip {
+        return Err(format!("{}: no data from offset {}", filename, header.vox_offset).into());
+    }
+
+    Ok((header, reader))
+}
+
+// Decode the values of the file one at a time, in file order, with the
+// scaling of the header applied, and hand them to `store` with their index.
+// Values are not buffered, so that large fields are only held once.
+fn read_values<R: Read, F: FnMut(usize, f64)>(
+    filename: &str,
+    header: &Header,
+    reader: &mut R,
+    mut store: F,
+) -> Result<(), Box<dyn Error>> {
+    let count = header.dimensions.iter().product::<usize>();
+
+    // A slope of 0 means no scaling.
+    let scaled = header.slope != 0.0 && !(header.slope == 1.0 && header.intercept == 0.0);
+
+    for i in 0..count {
+        let value = if header.big_endian {
+            header.voxel_type.read::<BigEndian, _>(reader)
+        } else {
+            header.voxel_type.read::<LittleEndian, _>(reader)
+        };
+        let value = match value {
+            Ok(v) => v,
+            Err(_) => {
+                return Err(format!(
+                    "{}: expected {} values from offset {}, got {}",
+                    filename, count, header.vox_offset, i
+                )
+                .into())
+            }
+        };
+
+        store(
+            i,
+            if scaled {
+                value * header.slope + header.intercept
+            } else {
+                value
+            },
+        );
+    }
+
+    Ok(())
+}
+
+// Read only the header of a .nii or .nii.gz file, not its values.
+pub(crate) fn read_header(filename: &str) -> Result<Header, Box<dyn Error>> {
+    let (header, _) = open(filename)?;
+
+    Ok(header)
+}
+
+// Read the header and all the values of a .nii or .nii.gz file, in file
+// order, with the scaling of the header applied.
+pub(crate) fn read_file(filename: &str) -> Result<(Header, Vec<f64>), Box<dyn Error>> {
+    let (header, mut reader) = open(filename)?;
+
+    let mut values = Vec::with_capacity(header.dimensions.iter().product::<usize>());
+    read_values(filename, &header, &mut reader, |_, v| values.push(v))?;
+
+    Ok((header, values))
+}
+
+// Load a displacement field, stored either as a 5D vector image, with the
+// components along the fifth dimension, or as a 4D image of 3 volumes. The
+// displacements are expected in the world frame of the file, in millimeters.
+// Absolute fields, such as the y_ files of SPM, can be loaded using
+// `OrientedField::with_mode`.
+pub fn load_file(filename: &str) -> Result<OrientedField, Box<dyn Error>> {
+    load_file_with(filename, Convention::Ras, Convention::Ras)
+}
+
+// Same as `load_file`, the field being converted to `convention`, as done
+// by the MetaImage and NRRD readers. The displacements of the file may be
+// expressed in another convention than the RAS world of NIfTI, given by
+// `vectors`, which is the case of the LPS fields written by ITK and ANTs.
+pub fn load_file_with(
+    filename: &str,
+    vectors: Convention,
+    convention: Convention,
+) -> Result<OrientedField, Box<dyn Error>> {
+    let (header, mut reader) = open(filename)?;
+
+    match header.intent {
+        0 | INTENT_DISPLACEMENT | INTENT_VECTOR => (),
+        intent => return Err(format!("{}: unsupported intent code {}", filename, intent).into()),
+    }
+
+    let mut d = header.dimensions.clone();
+    d.resize(7, 1);
+    let layout = (d[3] == 1 && d[4] == K) || (header.dimensions.len() == 4 && d[3] == K);
+    if !layout || d[5..].iter().any(|&v| v > 1) {
+        return Err(format!(
+            "{}: expected {} components per voxel, got dimensions {:?}",
+            filename, K, header.dimensions
+        )
+        .into());
+    }
+
+    // Components are stored as separate volumes, GIS fields interleave them.
+    let grid = Grid::new(Point3dd([0.; K]), Point3dd([1.; K]), [d[0], d[1], d[2]]);
+    let count = grid.len();
+    let signs = vectors.convert(Convention::Ras, &Point3dd([1.; K]));
+    let mut displacements = vec![0f32; count * K];
+    read_values(filename, &header, &mut reader, |i, v| {
+        let k = i / count;
+        displacements[(i % count) * K + k] = (v * signs[k]) as f32;
+    })?;
+
+    let field = OrientedField::new(
+        GISTransform::from_grid(&grid, displacements)?,
+        header.to_world,
+    )?;
+
+    // Avoid copying large fields when there is nothing to convert.
+    if convention == Convention::Ras {
+        Ok(field)
+    } else {
+        Ok(field.change_convention(Convention::Ras, convention))
+    }
+}
+
+// Write a NIfTI-1 file, compressed when the name ends with .gz. Both the
+// sform and, when the matrix has orthogonal columns, the qform are set.
+fn write_file(
+    filename: &str,
+    dimensions: &[usize],
+    voxel_type: VoxelType,
+    intent: i32,
+    to_world: &AffineTransform,
+    values: &[f64],
+) -> Result<(), Box<dyn Error>> {
+    let mut h = vec![0u8; NIFTI1_OFFSET];
+    let m = to_world.matrix();
+    let t = to_world.offsets();
+
+    LittleEndian::write_i32(&mut h[0..], NIFTI1_SIZE);
+    LittleEndian::write_i16(&mut h[40..], dimensions.len() as i16);
+    for (i, d) in dimensions.iter().enumerate() {
+        LittleEndian::write_i16(&mut h[42 + 2 * i..], *d as i16);
+    }
+    LittleEndian::write_i16(&mut h[68..], intent as i16);
+    LittleEndian::write_i16(&mut h[70..], datatype(voxel_type));
+    LittleEndian::write_i16(&mut h[72..], (voxel_type.size() * 8) as i16);
+
+    // Voxel sizes are the norms of the columns of the matrix.
+    let mut pixdim = [1f64; 8];
+    for k in 0..K {
+        pixdim[k + 1] = (0..K).map(|r| m[r][k] * m[r][k]).sum::<f64>().sqrt();
+    }
+
+    let mut rotation = [[0f64; K]; K];
+    for (r, row) in rotation.iter_mut().enumerate() {
+        for (c, v) in row.iter_mut().enumerate() {
+            *v = m[r][c] / pixdim[c + 1];
+        }
+    }
+    if matrix::determinant(&rotation) < 0.0 {
+        pixdim[0] = -1.0;
+        for row in rotation.iter_mut() {
+            row[2] = -row[2];
+        }
+    }
+
+    let product = matrix::multiply(&matrix::transpose(&rotation), &rotation);
+    let identity = matrix::identity();
+    let orthogonal = (0..K).all(|r| (0..K).all(|c| (product[r][c] - identity[r][c]).abs() < 1e-6));
+
+    for (i, p) in pixdim.iter().enumerate() {
+        LittleEndian::write_f32(&mut h[76 + 4 * i..], *p as f32);
+    }
+    LittleEndian::write_f32(&mut h[108..], NIFTI1_OFFSET as f32);
+    LittleEndian::write_f32(&mut h[112..], 1.0);
+    h[123] = 2; // Millimeters
+
+    if orthogonal {
+        LittleEndian::write_i16(&mut h[252..], 1);
+        let q = matrix_quaternion(&rotation);
+        for (i, v) in q.iter().chain(t.iter()).enumerate() {
+            LittleEndian::write_f32(&mut h[256 + 4 * i..], *v as f32);
+        }
+    }
+
+    LittleEndian::write_i16(&mut h[254..], 1);
+    for r in 0..K {
+        for c in 0..K {
+            LittleEndian::write_f32(&mut h[280 + 4 * (4 * r + c)..], m[r][c] as f32);
+        }
+        LittleEndian::write_f32(&mut h[280 + 4 * (4 * r + 3)..], t[r] as f32);
+    }
+    h[344..348].copy_from_slice(b"n+1\0");
+
+    h.reserve(values.len() * voxel_type.size());
+    for v in values {
+        voxel_type.write::<LittleEndian, _>(&mut h, *v)?;
+    }
+
+    let file_out = BufWriter::new(File::create(filename)?);
+    if filename.ends_with(".gz") {
+        let mut encoder = GzEncoder::new(file_out, Compression::default());
+        encoder.write_all(&h)?;
+        encoder.finish()?.flush()?;
+    } else {
+        let mut file_out = file_out;
+        file_out.write_all(&h)?;
+        file_out.flush()?;
+    }
+
+    Ok(())
+}
+
+// Save the field as a 5D vector image of 32 bits floats, with the
+// displacement intent, or the vector one for absolute fields. `to_world`
+// maps the millimeter coordinates of the field to the world coordinates of
+// the file.
+fn write_field(
+    filename: &str,
+    field: &GISTransform,
+    to_world: &AffineTransform,
+) -> Result<(), Box<dyn Error>> {
+    let grid = field.grid();
+    let d = grid.dimensions;
+    let count = grid.len();
+
+    let mut values = vec![0f64; count * K];
+    for z in 0..d[2] {
+        for y in 0..d[1] {
+            for x in 0..d[0] {
+                let p = field.ctrl_point(x, y, z);
+                let i = grid.index(x, y, z);
+                for k in 0..K {
+                    values[k * count + i] = f64::from(p[k]);
+                }
+            }
+        }
+    }
+
+    let intent = match field.mode() {
+        FieldMode::Displacement => INTENT_DISPLACEMENT,
+        FieldMode::Absolute => INTENT_VECTOR,
+    };
+
+    write_file(
+        filename,
+        &[d[0], d[1], d[2], 1, K],
+        VoxelType::Float,
+        intent,
+        &voxel_to_world(&field.grid(), to_world),
+        &values,
+    )
+}
+
+pub fn save_file(filename: &str, field: &OrientedField) -> Result<(), Box<dyn Error>> {
+    write_field(filename, field.field(), field.to_world())
+}
+
+// Save a field, its millimeter coordinates being the world coordinates.
+pub fn save_gis(filename: &str, field: &GISTransform) -> Result<(), Box<dyn Error>> {
+    write_field(filename, field, &AffineTransform::identity())
+}
+
+pub fn save_volume(filename: &str, volume: &Volume) -> Result<(), Box<dyn Error>> {
+    save_oriented_volume(filename, volume, &AffineTransform::identity())
+}
+
+// Save a volume whose millimeter coordinates are mapped to world coordinates
+// by `to_world`, such as the jacobian map of an `OrientedField`.
+pub fn save_oriented_volume(
+    filename: &str,
+    volume: &Volume,
+    to_world: &AffineTransform,
+) -> Result<(), Box<dyn Error>> {
+    let grid = volume.grid();
+
+    write_file(
+        filename,
+        &grid.dimensions,
+        volume.voxel_type(),
+        0,
+        &voxel_to_world(grid, to_world),
+        volume.data(),
+    )
+}
+
+// Load a 3D scalar image, whose axes have to be aligned with the world axes.
+pub fn load_volume(filename: &str) -> Result<Volume, Box<dyn Error>> {
+    let (header, values) = read_file(filename)?;
+
+    let m = header.to_world.matrix();
+    let aligned = (0..K).all(|r| (0..K).all(|c| r == c || m[r][c] == 0.0));
+    if !aligned || header.dimensions.iter().skip(K).any(|&d| d > 1) {
+        return Err(format!(
+            "{}: only 3D volumes aligned with the world axes are supported",
+            filename
+        )
+        .into());
+    }
+
+    let mut d = header.dimensions.clone();
+    d.resize(K, 1);
+    let grid = Grid::new(
+        Point3dd(*header.to_world.offsets()),
+        Point3dd([m[0][0], m[1][1], m[2][2]]),
+        [d[0], d[1], d[2]],
+    );
+
+    Volume::new(grid, header.voxel_type, values)
+}
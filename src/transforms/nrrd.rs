@@ -0,0 +1,409 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+
+use byteorder::BigEndian;
+use byteorder::LittleEndian;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::volume::VoxelType;
+
+use super::affine::AffineTransform;
+use super::convention::Convention;
+use super::gis::FieldMode;
+use super::gis::GISTransform;
+use super::gis::Point3dd;
+use super::grid::Grid;
+use super::matrix;
+use super::oriented::voxel_to_world;
+use super::oriented::OrientedField;
+use super::K;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Raw,
+    Gzip,
+}
+
+fn voxel_type(name: &str) -> Option<VoxelType> {
+    match name {
+        "uchar" | "unsigned char" | "uint8" | "uint8_t" => Some(VoxelType::U8),
+        "signed char" | "int8" | "int8_t" => Some(VoxelType::S8),
+        "ushort" | "unsigned short" | "unsigned short int" | "uint16" | "uint16_t" => {
+            Some(VoxelType::U16)
+        }
+        "short" | "short int" | "signed short" | "signed short int" | "int16" | "int16_t" => {
+            Some(VoxelType::S16)
+        }
+        "uint" | "unsigned int" | "uint32" | "uint32_t" => Some(VoxelType::U32),
+        "int" | "signed int" | "int32" | "int32_t" => Some(VoxelType::S32),
+        "float" => Some(VoxelType::Float),
+        "double" => Some(VoxelType::Double),
+        _ => None,
+    }
+}
+
+// Sign of each axis of the space with respect to RAS.
+fn space_signs(name: &str) -> Option<[f64; K]> {
+    match name {
+        "right-anterior-superior" | "RAS" => Some(Convention::Ras.signs()),
+        "left-anterior-superior" | "LAS" => Some([-1.0, 1.0, 1.0]),
+        "left-posterior-superior" | "LPS" => Some(Convention::Lps.signs()),
+        _ => None,
+    }
+}
+
+// Vectors written as (x,y,z), separated by spaces, "none" giving None.
+fn parse_vectors(filename: &str, value: &str) -> Result<Vec<Option<[f64; K]>>, Box<dyn Error>> {
+    let mut vectors = vec![];
+
+    for v in value.split_whitespace() {
+        if v == "none" {
+            vectors.push(None);
+            continue;
+        }
+
+        let values = v
+            .trim_start_matches('(')
+            .trim_end_matches(')')
+            .split(',')
+            .map(|c| c.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()?;
+        if values.len() != K {
+            return Err(format!("{}: invalid vector {}", filename, v).into());
+        }
+
+        vectors.push(Some([values[0], values[1], values[2]]));
+    }
+
+    Ok(vectors)
+}
+
+// Load a grid transform, as written by 3D Slicer: a 4D image whose first
+// axis holds the 3 components of the displacements. The field is returned
+// in RAS, as for NIfTI fields, whatever the space of the file, and the
+// displacements are moved out of the measurement frame.
+pub fn load_file(filename: &str) -> Result<OrientedField, Box<dyn Error>> {
+    load_file_with(filename, Convention::Ras)
+}
+
+// Same as `load_file`, the field being converted to `convention`.
+pub fn load_file_with(
+    filename: &str,
+    convention: Convention,
+) -> Result<OrientedField, Box<dyn Error>> {
+    let mut bytes = vec![];
+    BufReader::new(File::open(filename)?).read_to_end(&mut bytes)?;
+
+    if !bytes.starts_with(b"NRRD000") {
+        return Err(format!("{}: not a NRRD file", filename).into());
+    }
+
+    let mut sizes = vec![];
+    let mut voxel = None;
+    let mut signs = None;
+    let mut directions = vec![];
+    let mut origin = [0.0; K];
+    let mut frame = matrix::identity();
+    let mut kinds = vec![];
+    let mut big_endian = false;
+    let mut encoding = None;
+    let mut data_file = None;
+    let mut position = 0;
+
+    // The header ends with an empty line.
+    while position < bytes.len() {
+        let end = bytes[position..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(bytes.len(), |e| position + e + 1);
+        let line = String::from_utf8_lossy(&bytes[position..end]).to_string();
+        position = end;
+
+        let line = line.trim_end_matches(&['\r', '\n'][..]);
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+
+        let (key, value) = match line.find(": ") {
+            Some(i) => (&line[..i], line[i + 2..].trim()),
+            None => continue, // Magic line, or key/value pair
+        };
+
+        match key {
+            "dimension" if value != "4" => {
+                return Err(format!("{}: expected 4 dimensions, got {}", filename, value).into())
+            }
+            "type" => voxel = voxel_type(value),
+            "sizes" => {
+                sizes = value
+                    .split_whitespace()
+                    .map(|v| v.parse::<usize>())
+                    .collect::<Result<Vec<_>, _>>()?
+            }
+            "space" => {
+                signs = match space_signs(value) {
+                    Some(s) => Some(s),
+                    None => return Err(format!("{}: unsupported space {}", filename, value).into()),
+                }
+            }
+            "space directions" => directions = parse_vectors(filename, value)?,
+            "space origin" => {
+                if let Some(Some(o)) = parse_vectors(filename, value)?.first() {
+                    origin = *o;
+                }
+            }
+            "measurement frame" => {
+                // Each vector is a column of the frame.
+                for (c, v) in parse_vectors(filename, value)?.iter().take(K).enumerate() {
+                    if let Some(v) = v {
+                        for (r, row) in frame.iter_mut().enumerate() {
+                            row[c] = v[r];
+                        }
+                    }
+                }
+            }
+            "kinds" => kinds = value.split_whitespace().map(String::from).collect(),
+            "endian" => big_endian = value == "big",
+            "encoding" => encoding = Some(value.to_string()),
+            "data file" | "datafile" => data_file = Some(value.to_string()),
+            _ => (),
+        }
+    }
+
+    let voxel_type = match voxel {
+        Some(t) => t,
+        None => return Err(format!("{}: missing or unsupported type", filename).into()),
+    };
+    let signs = match signs {
+        Some(s) => s,
+        None => return Err(format!("{}: missing space", filename).into()),
+    };
+    let vector = kinds.first().is_none_or(|k| k.ends_with("vector"));
+    if sizes.len() != K + 1 || sizes[0] != K || !vector || directions.len() != K + 1 {
+        return Err(format!(
+            "{}: expected a vector axis of {} components followed by {} spatial axes",
+            filename, K, K
+        )
+        .into());
+    }
+
+    let mut data = match data_file {
+        Some(name) => {
+            let path = Path::new(filename).with_file_name(name);
+            let mut data = vec![];
+            BufReader::new(File::open(path)?).read_to_end(&mut data)?;
+            data
+        }
+        None => bytes.split_off(position),
+    };
+
+    match encoding.as_deref() {
+        Some("raw") => (),
+        Some("gzip") | Some("gz") => {
+            let mut decoded = vec![];
+            GzDecoder::new(&data[..]).read_to_end(&mut decoded)?;
+            data = decoded;
+        }
+        _ => return Err(format!("{}: missing or unsupported encoding", filename).into()),
+    }
+
+    let grid = Grid::new(
+        Point3dd([0.; K]),
+        Point3dd([1.; K]),
+        [sizes[1], sizes[2], sizes[3]],
+    );
+    let count = grid.len() * K;
+    if count * voxel_type.size() > data.len() {
+        return Err(format!(
+            "{}: expected {} bytes of data, got {}",
+            filename,
+            count * voxel_type.size(),
+            data.len()
+        )
+        .into());
+    }
+
+    // Displacements to the space of the file, and then to `convention`.
+    let target = convention.signs();
+    let mut to_target = frame;
+    for (r, row) in to_target.iter_mut().enumerate() {
+        for v in row.iter_mut() {
+            *v *= signs[r] * target[r];
+        }
+    }
+
+    let mut reader = &data[..];
+    let mut displacements = Vec::with_capacity(count);
+    for _ in 0..grid.len() {
+        let mut v = [0f64; K];
+        for c in v.iter_mut() {
+            *c = if big_endian {
+                voxel_type.read::<BigEndian, _>(&mut reader)?
+            } else {
+                voxel_type.read::<LittleEndian, _>(&mut reader)?
+            };
+        }
+
+        for c in matrix::apply(&to_target, &v).iter() {
+            displacements.push(*c as f32);
+        }
+    }
+
+    let mut matrix = [[0f64; K]; K];
+    for (c, direction) in directions.iter().skip(1).enumerate() {
+        let direction = match direction {
+            Some(d) => d,
+            None => return Err(format!("{}: missing space direction", filename).into()),
+        };
+        for r in 0..K {
+            matrix[r][c] = direction[r] * signs[r] * target[r];
+        }
+    }
+    for (r, o) in origin.iter_mut().enumerate() {
+        *o *= signs[r] * target[r];
+    }
+
+    OrientedField::new(
+        GISTransform::from_grid(&grid, displacements)?,
+        AffineTransform::new(matrix, origin),
+    )
+}
+
+// Save the displacements of the field as 32 bits floats. `to_world` maps
+// the millimeter coordinates of the field to `space`, either RAS or LPS.
+fn write_field(
+    filename: &str,
+    field: &GISTransform,
+    to_world: &AffineTransform,
+    space: Convention,
+    encoding: Encoding,
+) -> Result<(), Box<dyn Error>> {
+    let space = match space {
+        Convention::Ras => "right-anterior-superior",
+        Convention::Lps => "left-posterior-superior",
+        Convention::Lpi => return Err("NRRD has no left-posterior-inferior space".into()),
+    };
+    let d = field.dimensions();
+    let to_world = voxel_to_world(&field.grid(), to_world);
+    let m = to_world.matrix();
+    let o = to_world.offsets();
+    let directions = (0..K)
+        .map(|c| format!("({},{},{})", m[0][c], m[1][c], m[2][c]))
+        .collect::<Vec<_>>();
+
+    let mut file_out = BufWriter::new(File::create(filename)?);
+    writeln!(file_out, "NRRD0004")?;
+    writeln!(file_out, "type: float")?;
+    writeln!(file_out, "dimension: 4")?;
+    writeln!(file_out, "space: {}", space)?;
+    writeln!(file_out, "sizes: {} {} {} {}", K, d[0], d[1], d[2])?;
+    writeln!(file_out, "space directions: none {}", directions.join(" "))?;
+    writeln!(file_out, "kinds: vector domain domain domain")?;
+    writeln!(file_out, "endian: little")?;
+    match encoding {
+        Encoding::Raw => writeln!(file_out, "encoding: raw")?,
+        Encoding::Gzip => writeln!(file_out, "encoding: gzip")?,
+    }
+    writeln!(file_out, "space origin: ({},{},{})", o[0], o[1], o[2])?;
+    writeln!(file_out)?;
+
+    let mut data = Vec::with_capacity(d[0] * d[1] * d[2] * K * 4);
+    for z in 0..d[2] {
+        for y in 0..d[1] {
+            for x in 0..d[0] {
+                let p = field.ctrl_point(x, y, z);
+                for k in 0..K {
+                    VoxelType::Float.write::<LittleEndian, _>(&mut data, f64::from(p[k]))?;
+                }
+            }
+        }
+    }
+
+    match encoding {
+        Encoding::Raw => file_out.write_all(&data)?,
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(&mut file_out, Compression::default());
+            encoder.write_all(&data)?;
+            encoder.finish()?;
+        }
+    }
+    file_out.flush()?;
+
+    Ok(())
+}
+
+// The world coordinates of the field are expected to be in RAS, as for
+// NIfTI fields, and are written as such.
+pub fn save_file(
+    filename: &str,
+    field: &OrientedField,
+    encoding: Encoding,
+) -> Result<(), Box<dyn Error>> {
+    save_file_with(filename, field, Convention::Ras, encoding)
+}
+
+// Same as `save_file`, for a field in `convention`. RAS and LPS fields are
+// written in their own space, others in the LPS space of Slicer. Absolute
+// fields are converted to displacements.
+pub fn save_file_with(
+    filename: &str,
+    field: &OrientedField,
+    convention: Convention,
+    encoding: Encoding,
+) -> Result<(), Box<dyn Error>> {
+    if field.field().mode() == FieldMode::Absolute {
+        return save_file_with(filename, &field.to_displacement(), convention, encoding);
+    }
+
+    match convention {
+        Convention::Ras | Convention::Lps => write_field(
+            filename,
+            field.field(),
+            field.to_world(),
+            convention,
+            encoding,
+        ),
+        Convention::Lpi => {
+            let field = field.change_convention(convention, Convention::Lps);
+            write_field(
+                filename,
+                field.field(),
+                field.to_world(),
+                Convention::Lps,
+                encoding,
+            )
+        }
+    }
+}
+
+// Save a field, its millimeter coordinates being RAS coordinates.
+pub fn save_gis(
+    filename: &str,
+    field: &GISTransform,
+    encoding: Encoding,
+) -> Result<(), Box<dyn Error>> {
+    let identity = AffineTransform::identity();
+
+    match field.mode() {
+        FieldMode::Displacement => {
+            write_field(filename, field, &identity, Convention::Ras, encoding)
+        }
+        FieldMode::Absolute => write_field(
+            filename,
+            &field.to_displacement(),
+            &identity,
+            Convention::Ras,
+            encoding,
+        ),
+    }
+}
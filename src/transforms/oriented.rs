@@ -0,0 +1,185 @@
+use std::error::Error;
+
+use super::affine::AffineTransform;
+use crate::volume::Volume;
+use crate::volume::VoxelType;
+
+use super::gis;
+use super::gis::FieldMode;
+use super::gis::GISTransform;
+use super::gis::Point3dd;
+use super::grid::Grid;
+use super::matrix;
+use super::matrix::Matrix;
+use super::region::BoundingBox;
+use super::Transform;
+use super::K;
+
+// Displacement field sampled on a grid of arbitrary orientation, as stored
+// by NIfTI and ITK files. `to_world` maps the millimeter coordinates of the
+// underlying field to world coordinates, while the displacements themselves
+// are expressed in the world frame, as are the positions of absolute fields.
+#[derive(Debug)]
+pub struct OrientedField {
+    field: GISTransform,
+    to_world: AffineTransform,
+    to_field: AffineTransform,
+}
+
+impl OrientedField {
+    pub fn new(field: GISTransform, to_world: AffineTransform) -> Result<Self, Box<dyn Error>> {
+        let to_field = match to_world.inverted() {
+            Some(t) => t,
+            None => return Err(format!("Singular orientation {:?}", to_world).into()),
+        };
+
+        Ok(OrientedField {
+            field,
+            to_world,
+            to_field,
+        })
+    }
+
+    pub fn field(&self) -> &GISTransform {
+        &self.field
+    }
+
+    pub fn to_world(&self) -> &AffineTransform {
+        &self.to_world
+    }
+
+    // Interpret the stored values according to `mode`, see `GISTransform`.
+    pub fn with_mode(mut self, mode: FieldMode) -> Self {
+        self.field = self.field.with_mode(mode);
+        self
+    }
+
+    // Same field, storing the displacements.
+    pub fn to_displacement(&self) -> Self {
+        self.converted(FieldMode::Displacement)
+    }
+
+    // Same field, storing the transformed positions.
+    pub fn to_absolute(&self) -> Self {
+        self.converted(FieldMode::Absolute)
+    }
+
+    fn converted(&self, mode: FieldMode) -> Self {
+        let to_world = &self.to_world;
+        let field = self.field.converted(mode, |p| to_world.transform(&p));
+
+        OrientedField {
+            field,
+            to_world: AffineTransform::new(*to_world.matrix(), *to_world.offsets()),
+            to_field: AffineTransform::new(*self.to_field.matrix(), *self.to_field.offsets()),
+        }
+    }
+
+    // Interpolated displacement at p, both in world coordinates.
+    pub fn displacement(&self, p: &Point3dd) -> Point3dd {
+        let mut d = self.deformation(p);
+        for k in 0..K {
+            d.0[k] -= p[k];
+        }
+
+        d
+    }
+
+    pub fn deformation(&self, p: &Point3dd) -> Point3dd {
+        let q = self.to_field.transform(p);
+
+        // Absolute values are already positions in world coordinates.
+        match self.field.mode() {
+            FieldMode::Absolute => self.field.deformation(&q),
+            FieldMode::Displacement => {
+                let mut t = p.clone();
+
+                t += self.field.displacement(&q);
+
+                t
+            }
+        }
+    }
+}
+
+impl OrientedField {
+    // Jacobian matrix of `deformation` at p, in world coordinates, see
+    // `GISTransform::jacobian`. Differences are taken along the axes of the
+    // field, as the deformation is not smoother along the world axes.
+    pub fn jacobian(&self, p: &Point3dd) -> Matrix {
+        let s = self.field.spacing();
+        let m = self.to_world.matrix();
+        let mut steps = [[0f64; K]; K];
+        for (r, row) in steps.iter_mut().enumerate() {
+            for (c, v) in row.iter_mut().enumerate() {
+                *v = m[r][c] * s[c] / 2.0;
+            }
+        }
+
+        gis::jacobian(|q| self.deformation(q), p, &steps)
+    }
+
+    // Determinant of the jacobian on every control point, on the grid of
+    // the field: `to_world` positions the map in world coordinates.
+    pub fn jacobian_map(&self) -> Volume {
+        let grid = self.field.grid();
+        let d = grid.dimensions;
+        let mut map = Volume::filled(grid.clone(), VoxelType::Float, 0.0);
+
+        for z in 0..d[2] {
+            for y in 0..d[1] {
+                for x in 0..d[0] {
+                    let p = self.to_world.transform(&grid.position(x, y, z));
+                    let j = self.jacobian(&p);
+                    map.data_mut()[grid.index(x, y, z)] = matrix::determinant(&j);
+                }
+            }
+        }
+
+        map
+    }
+}
+
+impl Transform for OrientedField {
+    fn transform(&self, p: &Point3dd) -> Point3dd {
+        self.deformation(p)
+    }
+
+    // Bounding box of the corners of the field domain, in world coordinates.
+    fn domain(&self) -> Option<BoundingBox> {
+        let domain = self.field.domain()?;
+        let mut b = BoundingBox::empty();
+
+        for corner in 0..(1 << K) {
+            let mut p = domain.low.clone();
+            for k in 0..K {
+                if corner & (1 << k) != 0 {
+                    p.0[k] = domain.high[k];
+                }
+            }
+            b.extend(&self.to_world.transform(&p));
+        }
+
+        Some(b)
+    }
+
+    fn inverse(&self) -> Option<Box<dyn Transform>> {
+        None
+    }
+}
+
+// Voxel indices of the grid to its millimeters, followed by `to_world`.
+pub(crate) fn voxel_to_world(grid: &Grid, to_world: &AffineTransform) -> AffineTransform {
+    let mut scaling = [[0f64; K]; K];
+    for (k, row) in scaling.iter_mut().enumerate() {
+        row[k] = grid.spacing[k];
+    }
+
+    let m = to_world.matrix();
+    let mut offsets = matrix::apply(m, &grid.origin.0);
+    for (o, t) in offsets.iter_mut().zip(to_world.offsets()) {
+        *o += t;
+    }
+
+    AffineTransform::new(matrix::multiply(m, &scaling), offsets)
+}
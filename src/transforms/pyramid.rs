@@ -0,0 +1,156 @@
+use super::gis::GISTransform;
+use super::gis::Point3dd;
+use super::K;
+
+#[derive(Debug)]
+struct Level {
+    field: GISTransform,
+    max_error: f64, // Bound on the distance to the full resolution result, in millimeters
+    nan_mismatch: usize, // Positions defined in the full resolution field, and NaN in this level
+    nan_loss: f64,  // Fraction of the positions defined in the full resolution field
+}
+
+// Progressively downsampled versions of a deformation field, each level
+// having twice the spacing of the previous one, level 0 being the original.
+#[derive(Debug)]
+pub struct FieldPyramid {
+    levels: Vec<Level>,
+}
+
+impl FieldPyramid {
+    // Build at most `levels` levels, stopping early when the field would
+    // become smaller than 2 control points along an axis.
+    pub fn new(field: GISTransform, levels: usize) -> Self {
+        let mut pyramid = FieldPyramid {
+            levels: vec![Level {
+                field,
+                max_error: 0.0,
+                nan_mismatch: 0,
+                nan_loss: 0.0,
+            }],
+        };
+
+        while pyramid.levels.len() < levels {
+            let previous = &pyramid.levels.last().unwrap().field;
+            if previous.dimensions().iter().take(K).any(|&d| d < 4) {
+                break;
+            }
+
+            let s = previous.spacing();
+            let field = previous.resample_spacing(Point3dd([s[0] * 2.0, s[1] * 2.0, s[2] * 2.0]));
+            let (max_error, nan_mismatch, defined) = pyramid.error_bound(&field);
+            let nan_loss = if defined > 0 {
+                nan_mismatch as f64 / defined as f64
+            } else {
+                0.0
+            };
+
+            pyramid.levels.push(Level {
+                field,
+                max_error,
+                nan_mismatch,
+                nan_loss,
+            });
+        }
+
+        pyramid
+    }
+
+    // The control points of the coarse field are also control points of the
+    // full resolution field, so on every cell of the latter the difference of
+    // both interpolations is trilinear, and its norm is maximal on the
+    // corners of the cell.
+    //
+    // Positions where the full resolution field is NaN are ignored. Those
+    // where only the coarse one is NaN, as holes grow with the cells, are
+    // counted instead, the bound not covering them, along with the number of
+    // positions defined in the full resolution field.
+    fn error_bound(&self, coarse: &GISTransform) -> (f64, usize, usize) {
+        let full = &self.levels[0].field;
+        let d = full.dimensions();
+        let s = full.spacing();
+        let o = full.origin();
+        let mut max = 0f64;
+        let mut nan_mismatch = 0;
+        let mut defined = 0;
+
+        // Include the far faces, as the last cells are constant in the full
+        // resolution field, and not in the coarse one.
+        for z in 0..=d[2] {
+            for y in 0..=d[1] {
+                for x in 0..=d[0] {
+                    let i = [x, y, z];
+                    let mut p = o.clone();
+                    for k in 0..K {
+                        let v = if i[k] == d[k] {
+                            i[k] as f64 - 1e-9
+                        } else {
+                            i[k] as f64
+                        };
+                        p.0[k] += v * s[k];
+                    }
+
+                    let a = full.displacement(&p);
+                    let b = coarse.displacement(&p);
+                    if a.is_nan() {
+                        continue;
+                    }
+                    defined += 1;
+                    if b.is_nan() {
+                        nan_mismatch += 1;
+                        continue;
+                    }
+
+                    let distance = (0..K).map(|k| (a[k] - b[k]).powi(2)).sum::<f64>();
+                    max = max.max(distance.sqrt());
+                }
+            }
+        }
+
+        (max, nan_mismatch, defined)
+    }
+
+    pub fn len(&self) -> usize {
+        self.levels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.levels.is_empty()
+    }
+
+    pub fn level(&self, level: usize) -> &GISTransform {
+        &self.levels[level].field
+    }
+
+    pub fn max_error(&self, level: usize) -> f64 {
+        self.levels[level].max_error
+    }
+
+    pub fn nan_mismatch(&self, level: usize) -> usize {
+        self.levels[level].nan_mismatch
+    }
+
+    // Fraction of the positions defined in the full resolution field which
+    // are NaN in this level, as holes grow with the cells.
+    pub fn nan_loss(&self, level: usize) -> f64 {
+        self.levels[level].nan_loss
+    }
+
+    // Coarsest level whose error bound is within the tolerance, in millimeters,
+    // and which loses at most the `nan_tolerance` fraction of the positions
+    // defined in the full resolution field. With a `nan_tolerance` of 0, the
+    // levels are only selected when they keep every defined position.
+    pub fn select(&self, tolerance: f64, nan_tolerance: f64) -> usize {
+        (0..self.levels.len())
+            .rev()
+            .find(|&l| {
+                self.levels[l].max_error <= tolerance && self.levels[l].nan_loss <= nan_tolerance
+            })
+            .unwrap_or(0)
+    }
+
+    pub fn deformation(&self, p: &Point3dd, tolerance: f64, nan_tolerance: f64) -> Point3dd {
+        self.level(self.select(tolerance, nan_tolerance))
+            .deformation(p)
+    }
+}
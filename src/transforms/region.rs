@@ -0,0 +1,258 @@
+use std::error::Error;
+
+use super::bounds::DisplacementPyramid;
+use super::gis::GISTransform;
+use super::gis::Point3dd;
+use super::matrix;
+use super::K;
+
+// Axis-aligned box, bounds are inclusive and expressed in millimeters.
+#[derive(Clone, Debug)]
+pub struct BoundingBox {
+    pub low: Point3dd,
+    pub high: Point3dd,
+}
+
+impl BoundingBox {
+    pub fn new(low: Point3dd, high: Point3dd) -> Self {
+        BoundingBox { low, high }
+    }
+
+    // The empty box, which is the neutral element of `extend`.
+    pub fn empty() -> Self {
+        BoundingBox {
+            low: Point3dd([f64::INFINITY; K]),
+            high: Point3dd([f64::NEG_INFINITY; K]),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        (0..K).any(|k| self.low[k] > self.high[k])
+    }
+
+    pub fn extend(&mut self, p: &Point3dd) {
+        for k in 0..K {
+            self.low.0[k] = self.low[k].min(p[k]);
+            self.high.0[k] = self.high[k].max(p[k]);
+        }
+    }
+
+    pub fn contains(&self, p: &Point3dd) -> bool {
+        (0..K).all(|k| self.low[k] <= p[k] && p[k] <= self.high[k])
+    }
+
+    pub fn intersects(&self, other: &BoundingBox) -> bool {
+        (0..K).all(|k| self.low[k] <= other.high[k] && other.low[k] <= self.high[k])
+    }
+
+    pub fn center(&self) -> Point3dd {
+        let mut c = Point3dd([0.; K]);
+        for k in 0..K {
+            c.0[k] = (self.low[k] + self.high[k]) / 2.0;
+        }
+
+        c
+    }
+
+    pub fn extent(&self) -> Point3dd {
+        let mut e = Point3dd([0.; K]);
+        for k in 0..K {
+            e.0[k] = self.high[k] - self.low[k];
+        }
+
+        e
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Sphere {
+    pub center: Point3dd,
+    pub radius: f64, // Expressed in millimeters
+}
+
+impl Sphere {
+    pub fn bounding_box(&self) -> BoundingBox {
+        let mut b = BoundingBox::new(self.center.clone(), self.center.clone());
+        for k in 0..K {
+            b.low.0[k] -= self.radius;
+            b.high.0[k] += self.radius;
+        }
+
+        b
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Ellipsoid {
+    pub center: Point3dd,
+    pub axes: [Point3dd; K], // Unit vectors, one per semi-axis
+    pub radii: [f64; K],     // Semi-axes lengths, in millimeters
+}
+
+#[derive(Debug)]
+pub struct TransformedSphere {
+    pub ellipsoid: Ellipsoid,       // Local linear approximation at the center
+    pub enclosing_sphere: Sphere,   // Guaranteed to contain the deformed sphere
+    pub enclosing_box: BoundingBox, // Guaranteed to contain the deformed sphere
+    pub nan: bool,                  // Part of the sphere may be deformed to NaN
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sampling {
+    Surface, // Only the faces of the box are sampled
+    Volume,  // The whole box, faces and interior, is sampled
+}
+
+#[derive(Debug)]
+pub struct TransformedBox {
+    pub bounds: BoundingBox, // Encloses the non-NaN samples, empty if none
+    pub samples: usize,
+    pub nan_samples: usize,
+}
+
+impl GISTransform {
+    // Map a source box to the enclosing box of its deformed samples.
+    //
+    // `density` is the number of samples taken along each axis, including
+    // both faces. This is an approximation: extremes falling between
+    // samples are missed, see `DisplacementPyramid` for guaranteed bounds.
+    pub fn transform_box(
+        &self,
+        source: &BoundingBox,
+        density: usize,
+        sampling: Sampling,
+    ) -> TransformedBox {
+        let density = density.max(2);
+        let extent = source.extent();
+
+        let mut steps = [0usize; K];
+        let mut delta = [0f64; K];
+        for k in 0..K {
+            // Degenerate axes only need a single sample.
+            if extent[k] > 0.0 {
+                steps[k] = density;
+                delta[k] = extent[k] / (density - 1) as f64;
+            } else {
+                steps[k] = 1;
+            }
+        }
+
+        let mut result = TransformedBox {
+            bounds: BoundingBox::empty(),
+            samples: 0,
+            nan_samples: 0,
+        };
+
+        let mut sample = |i: [usize; K]| {
+            // Use the bounds as-is on the last sample to avoid rounding errors.
+            let mut p = Point3dd([0.; K]);
+            for k in 0..K {
+                p.0[k] = if i[k] + 1 == steps[k] && steps[k] > 1 {
+                    source.high[k]
+                } else {
+                    source.low[k] + i[k] as f64 * delta[k]
+                };
+            }
+
+            let t = self.deformation(&p);
+            result.samples += 1;
+
+            if t.is_nan() {
+                result.nan_samples += 1;
+            } else {
+                result.bounds.extend(&t);
+            }
+        };
+
+        let last = [steps[0] - 1, steps[1] - 1, steps[2] - 1];
+        for z in 0..steps[2] {
+            for y in 0..steps[1] {
+                // Away from the faces along y and z, only the two faces along
+                // x are sampled, so that the interior is never visited.
+                let inside = y > 0 && y < last[1] && z > 0 && z < last[2];
+                if sampling == Sampling::Surface && inside {
+                    sample([0, y, z]);
+                    if last[0] > 0 {
+                        sample([last[0], y, z]);
+                    }
+                    continue;
+                }
+
+                for x in 0..steps[0] {
+                    sample([x, y, z]);
+                }
+            }
+        }
+
+        result
+    }
+}
+
+impl GISTransform {
+    // Map a source sphere through the deformation field.
+    //
+    // The ellipsoid is the image of the sphere by the tangent map at its
+    // center, which is only an approximation. The enclosing sphere and box
+    // are conservative, and derived from the displacement bounds of the
+    // region, so `pyramid` has to be built from this deformation field, an
+    // error being returned when its grid or mode differ.
+    pub fn transform_sphere(
+        &self,
+        source: &Sphere,
+        pyramid: &DisplacementPyramid,
+    ) -> Result<TransformedSphere, Box<dyn Error>> {
+        if !pyramid.matches(self) {
+            return Err("The displacement pyramid was built from another field".into());
+        }
+
+        let center = self.deformation(&source.center);
+        let jacobian = self.jacobian(&source.center);
+
+        // The unit sphere is mapped by J to the ellipsoid x^T (J J^T)^-1 x = 1,
+        // whose semi-axes are the eigenvectors of J J^T, of length sqrt(eigenvalue).
+        let shape = matrix::multiply(&jacobian, &matrix::transpose(&jacobian));
+        let (values, vectors) = matrix::symmetric_eigen(&shape);
+
+        let mut axes = [Point3dd([0.; K]), Point3dd([0.; K]), Point3dd([0.; K])];
+        let mut radii = [0.; K];
+        for j in 0..K {
+            for (k, row) in vectors.iter().enumerate() {
+                axes[j].0[k] = row[j];
+            }
+            radii[j] = source.radius * values[j].max(0.0).sqrt();
+        }
+
+        let region = source.bounding_box();
+        let range = pyramid.displacement_range(&region);
+        let enclosing_box = pyramid.enclosing_box(&region);
+
+        // |p + d - (c + m)| <= |p - c| + |d - m|, with m the middle of the
+        // displacement range.
+        let mut enclosing_sphere = Sphere {
+            center: source.center.clone(),
+            radius: f64::NAN,
+        };
+        if !range.is_empty() {
+            let mut half = 0.0;
+            for k in 0..K {
+                enclosing_sphere.center.0[k] += (range.min[k] + range.max[k]) / 2.0;
+                half += ((range.max[k] - range.min[k]) / 2.0).powi(2);
+            }
+            let radius = source.radius + half.sqrt();
+            enclosing_sphere.radius = radius + 8.0 * f64::EPSILON * radius.max(1.0);
+        } else {
+            enclosing_sphere.center = Point3dd([f64::NAN; K]);
+        }
+
+        Ok(TransformedSphere {
+            ellipsoid: Ellipsoid {
+                center,
+                axes,
+                radii,
+            },
+            enclosing_sphere,
+            enclosing_box,
+            nan: range.nan,
+        })
+    }
+}
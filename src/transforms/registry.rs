@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use super::affine::AffineTransform;
+use super::chain::TransformChain;
+use super::gis::GISTransform;
+use super::gis::Point3dd;
+use super::region::BoundingBox;
+use super::Transform;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Kind {
+    Affine,
+    Gis,
+    Chain,
+}
+
+#[derive(Deserialize, Debug)]
+struct EdgeConfig {
+    source: String,
+    target: String,
+    #[serde(rename = "type")]
+    kind: Kind,
+    file: String,
+}
+
+// Configuration file format:
+//
+//      { "transforms": [
+//          { "source": "<space>", "target": "<space>", "type": "gis", "file": "<basename>" },
+//          { "source": "<space>", "target": "<space>", "type": "affine", "file": "<file.trm>" },
+//          { "source": "<space>", "target": "<space>", "type": "chain", "file": "<manifest>" }
+//      ]}
+//
+// Relative paths are resolved from the directory of the configuration file.
+#[derive(Deserialize, Debug)]
+struct Config {
+    transforms: Vec<EdgeConfig>,
+}
+
+#[derive(Debug)]
+struct Edge {
+    source: String,
+    target: String,
+    inverted: bool, // Computed from an edge registered in the other direction
+    transform: Box<dyn Transform>,
+}
+
+// Graph of spaces, whose edges are the transforms between them.
+#[derive(Debug, Default)]
+pub struct SpaceRegistry {
+    edges: Vec<Edge>,
+}
+
+impl SpaceRegistry {
+    pub fn new() -> Self {
+        SpaceRegistry { edges: vec![] }
+    }
+
+    // Register a transform, as well as its inverse when available.
+    pub fn insert(&mut self, source: &str, target: &str, transform: Box<dyn Transform>) {
+        if let Some(inverse) = transform.inverse() {
+            self.edges.push(Edge {
+                source: target.to_string(),
+                target: source.to_string(),
+                inverted: true,
+                transform: inverse,
+            });
+        }
+
+        self.edges.push(Edge {
+            source: source.to_string(),
+            target: target.to_string(),
+            inverted: false,
+            transform,
+        });
+    }
+
+    pub fn load_file(filename: &str) -> Result<Self, Box<dyn Error>> {
+        let file_in = BufReader::new(File::open(filename)?);
+        let config: Config = serde_json::from_reader(file_in)?;
+
+        let directory = Path::new(filename)
+            .parent()
+            .unwrap_or_else(|| Path::new(""));
+        let mut registry = Self::new();
+
+        for edge in config.transforms {
+            let path = directory.join(&edge.file);
+            let path = path.to_string_lossy();
+
+            let transform: Box<dyn Transform> = match edge.kind {
+                Kind::Affine => Box::new(AffineTransform::load_file(&path)?),
+                Kind::Gis => Box::new(GISTransform::load_file(&path)?),
+                Kind::Chain => Box::new(TransformChain::load_file(&path)?),
+            };
+
+            registry.insert(&edge.source, &edge.target, transform);
+        }
+
+        Ok(registry)
+    }
+
+    pub fn spaces(&self) -> Vec<&str> {
+        let mut spaces = self
+            .edges
+            .iter()
+            .flat_map(|e| vec![e.source.as_str(), e.target.as_str()])
+            .collect::<Vec<_>>();
+        spaces.sort_unstable();
+        spaces.dedup();
+
+        spaces
+    }
+
+    // Pairs of spaces with a transform registered from the first to the
+    // second, in the order they were inserted.
+    pub fn registered(&self) -> Vec<(&str, &str)> {
+        self.edges
+            .iter()
+            .filter(|e| !e.inverted)
+            .map(|e| (e.source.as_str(), e.target.as_str()))
+            .collect()
+    }
+
+    // Region of the source space which can be mapped to the target one, as
+    // far as the first transform of the path is concerned.
+    pub fn domain(&self, source: &str, target: &str) -> Option<BoundingBox> {
+        self.edges_path(source, target)?.first()?.transform.domain()
+    }
+
+    // Shortest sequence of edges from source to target. Among the paths of
+    // the same length, the one going through the fewest inverted transforms
+    // is chosen.
+    fn edges_path(&self, source: &str, target: &str) -> Option<Vec<&Edge>> {
+        // Number of inverted edges on the best path found to each space, and
+        // the last edge of that path.
+        let mut previous: HashMap<&str, (usize, Option<&Edge>)> = HashMap::new();
+        let mut level = vec![source];
+
+        previous.insert(source, (0, None));
+
+        // Spaces are reached one level at a time, so that every path of the
+        // same length is compared before the next level is built.
+        while !level.is_empty() && !previous.contains_key(target) {
+            let mut next: HashMap<&str, (usize, Option<&Edge>)> = HashMap::new();
+            let mut reached = vec![];
+
+            for space in level {
+                let inverted = previous[space].0;
+
+                for edge in self.edges.iter().filter(|e| e.source == space) {
+                    let space = edge.target.as_str();
+                    if previous.contains_key(space) {
+                        continue;
+                    }
+
+                    let cost = inverted + edge.inverted as usize;
+                    match next.get(space) {
+                        Some((best, _)) if *best <= cost => (),
+                        best => {
+                            if best.is_none() {
+                                reached.push(space);
+                            }
+                            next.insert(space, (cost, Some(edge)));
+                        }
+                    }
+                }
+            }
+
+            previous.extend(next);
+            level = reached;
+        }
+
+        let mut path = vec![];
+        let mut space = target;
+        loop {
+            match previous.get(space)?.1 {
+                None => break,
+                Some(edge) => {
+                    path.push(edge);
+                    space = &edge.source;
+                }
+            }
+        }
+        path.reverse();
+
+        Some(path)
+    }
+
+    // Spaces traversed from source to target, both included.
+    pub fn path(&self, source: &str, target: &str) -> Option<Vec<String>> {
+        let edges = self.edges_path(source, target)?;
+        let mut spaces = vec![source.to_string()];
+        spaces.extend(edges.iter().map(|e| e.target.clone()));
+
+        Some(spaces)
+    }
+
+    pub fn transform_points(
+        &self,
+        source: &str,
+        target: &str,
+        points: &[Point3dd],
+    ) -> Result<Vec<Point3dd>, Box<dyn Error>> {
+        let edges = match self.edges_path(source, target) {
+            Some(edges) => edges,
+            None => return Err(format!("No path from {} to {}", source, target).into()),
+        };
+
+        let mut points = points.to_vec();
+        for edge in edges {
+            points = edge.transform.transform_batch(&points);
+        }
+
+        Ok(points)
+    }
+}
+
+pub fn load_file(filename: &str) -> Result<SpaceRegistry, Box<dyn Error>> {
+    SpaceRegistry::load_file(filename)
+}
@@ -0,0 +1,96 @@
+use super::gis::GISTransform;
+use super::gis::Point3dd;
+use super::grid::Grid;
+use super::K;
+
+// Offsets, in millimeters, and weights of a tent filter as wide as the
+// output spacing, sampled at the input spacing. Only needed when
+// downsampling, otherwise the field is sampled as-is.
+fn tent_filter(input: f64, output: f64) -> Vec<(f64, f64)> {
+    if output <= input {
+        return vec![(0.0, 1.0)];
+    }
+
+    let m = (output / input).floor() as i64;
+    (-m..=m)
+        .map(|j| j as f64 * input)
+        .filter(|offset| offset.abs() < output)
+        .map(|offset| (offset, 1.0 - offset.abs() / output))
+        .collect()
+}
+
+impl GISTransform {
+    // Resample the displacement field on a new grid.
+    //
+    // When the new grid is coarser than the field along an axis, the
+    // displacements are low-pass filtered first, to avoid aliasing.
+    // Positions whose displacement is NaN stay NaN, while NaN neighbours
+    // are ignored by the filter.
+    pub fn resample(&self, grid: &Grid) -> GISTransform {
+        let mut filters = vec![];
+        for k in 0..K {
+            filters.push(tent_filter(self.spacing()[k], grid.spacing[k]));
+        }
+
+        let d = &grid.dimensions;
+        let mut displacements = Vec::with_capacity(grid.len() * K);
+
+        for z in 0..d[2] {
+            for y in 0..d[1] {
+                for x in 0..d[0] {
+                    let p = grid.position(x, y, z);
+                    let value = self.filtered_displacement(&p, &filters);
+
+                    for k in 0..K {
+                        displacements.push(value[k] as f32);
+                    }
+                }
+            }
+        }
+
+        // The number of values matches the grid by construction.
+        GISTransform::from_grid(grid, displacements).unwrap()
+    }
+
+    // Resample the field with a new spacing, keeping the same origin and
+    // covering at least the same extent.
+    pub fn resample_spacing(&self, spacing: Point3dd) -> GISTransform {
+        let extent = self.dimensions_mm();
+        let mut dimensions = [0; K];
+        for k in 0..K {
+            dimensions[k] = (extent[k] / spacing[k]).ceil().max(1.0) as usize;
+        }
+
+        self.resample(&Grid::new(self.origin().clone(), spacing, dimensions))
+    }
+
+    fn filtered_displacement(&self, p: &Point3dd, filters: &[Vec<(f64, f64)>]) -> Point3dd {
+        let center = self.displacement(p);
+        if center.is_nan() || filters.iter().all(|f| f.len() == 1) {
+            return center;
+        }
+
+        let mut sum = Point3dd([0.; K]);
+        let mut weights = 0.0;
+
+        for (dz, wz) in &filters[2] {
+            for (dy, wy) in &filters[1] {
+                for (dx, wx) in &filters[0] {
+                    let q = Point3dd([p[0] + dx, p[1] + dy, p[2] + dz]);
+                    let mut v = self.displacement(&q);
+
+                    if !v.is_nan() {
+                        let w = wx * wy * wz;
+                        v.scale(w);
+                        sum += v;
+                        weights += w;
+                    }
+                }
+            }
+        }
+
+        sum.scale(1.0 / weights);
+
+        sum
+    }
+}
@@ -0,0 +1,230 @@
+use std::error::Error;
+
+use super::affine::AffineTransform;
+use super::gis::FieldMode;
+use super::gis::GISTransform;
+use super::gis::Point3dd;
+use super::matrix;
+use super::oriented::voxel_to_world;
+use super::oriented::OrientedField;
+use super::Transform;
+use super::K;
+
+// Displacements on the nodes of the field grid, kept in double precision
+// while squaring, as rounding errors would add up otherwise.
+struct Nodes<'a> {
+    field: &'a GISTransform,
+    values: Vec<[f64; K]>,
+}
+
+impl<'a> Nodes<'a> {
+    fn index(&self, i: usize, j: usize, k: usize) -> usize {
+        let d = self.field.dimensions();
+        i + d[0] * (j + d[1] * k)
+    }
+
+    // Trilinear interpolation at p, in millimeters of the field. Positions
+    // are clamped to the grid, so that points leaving the field keep the
+    // displacement of its border.
+    fn interpolate(&self, p: &Point3dd) -> [f64; K] {
+        let d = self.field.dimensions();
+        let s = self.field.spacing();
+        let o = self.field.origin();
+        let mut low = [0; K];
+        let mut weight = [0.0; K];
+
+        for k in 0..K {
+            let max = (d[k] - 1) as f64;
+            let v = ((p[k] - o[k]) / s[k]).max(0.0).min(max);
+            low[k] = (v.floor() as usize).min(d[k].max(2) - 2);
+            weight[k] = v - low[k] as f64;
+        }
+
+        let mut value = [0.0; K];
+        for dz in 0..2 {
+            for dy in 0..2 {
+                for dx in 0..2 {
+                    let i = [low[0] + dx, low[1] + dy, low[2] + dz];
+                    if (0..K).any(|k| i[k] >= d[k]) {
+                        continue;
+                    }
+
+                    let w = [dx, dy, dz]
+                        .iter()
+                        .enumerate()
+                        .map(|(k, &o)| if o == 0 { 1.0 - weight[k] } else { weight[k] })
+                        .product::<f64>();
+                    let n = &self.values[self.index(i[0], i[1], i[2])];
+                    for k in 0..K {
+                        value[k] += w * n[k];
+                    }
+                }
+            }
+        }
+
+        value
+    }
+}
+
+// Velocities needing more squarings than this, that is more than 2^64 times
+// the distance between nodes, are rejected rather than looped over.
+const MAX_SQUARINGS: i32 = 64;
+
+// Exponential of the stationary velocity field, multiplied by `scale`,
+// computed by scaling and squaring. `to_world` maps the millimeters of the
+// field to the frame of the velocities, in which the nodes are moved.
+fn exponential(
+    field: &GISTransform,
+    to_world: &AffineTransform,
+    scale: f64,
+) -> Result<GISTransform, Box<dyn Error>> {
+    if field.mode() == FieldMode::Absolute {
+        return Err("Velocity fields cannot hold absolute positions".into());
+    }
+
+    let grid = field.grid();
+    let d = grid.dimensions;
+    // Only called with invertible matrices, see `OrientedField::new`.
+    let to_field = to_world.inverted().unwrap();
+
+    let mut nodes = Nodes {
+        field,
+        values: Vec::with_capacity(grid.len()),
+    };
+    let mut positions = Vec::with_capacity(grid.len());
+    for z in 0..d[2] {
+        for y in 0..d[1] {
+            for x in 0..d[0] {
+                let v = field.ctrl_point(x, y, z);
+                nodes
+                    .values
+                    .push([f64::from(v[0]), f64::from(v[1]), f64::from(v[2])]);
+                positions.push(to_world.transform(&grid.position(x, y, z)));
+            }
+        }
+    }
+
+    // Scale the velocities down until they move points by less than half
+    // the smallest distance between nodes, in the frame of the velocities.
+    let mut step = f64::INFINITY;
+    for (k, s) in grid.spacing.0.iter().enumerate() {
+        let m = to_world.matrix();
+        let length = (0..K).map(|r| (m[r][k] * s).powi(2)).sum::<f64>().sqrt();
+        step = step.min(length);
+    }
+    let mut max = 0f64;
+    for v in &nodes.values {
+        let norm = v.iter().map(|c| c * c).sum::<f64>().sqrt() * scale.abs();
+        if norm.is_infinite() {
+            return Err("Velocity fields cannot hold infinite values".into());
+        }
+        if !norm.is_nan() {
+            max = max.max(norm);
+        }
+    }
+    let squarings = if max > 0.0 {
+        (max / (0.5 * step)).log2().ceil().max(0.0)
+    } else {
+        0.0
+    };
+    if squarings > f64::from(MAX_SQUARINGS) {
+        return Err(format!(
+            "Velocities of {} need {} squarings, more than {}",
+            max, squarings, MAX_SQUARINGS
+        )
+        .into());
+    }
+    let squarings = squarings as i32;
+
+    let factor = scale / 2f64.powi(squarings);
+    for v in nodes.values.iter_mut() {
+        for c in v.iter_mut() {
+            *c *= factor;
+        }
+    }
+
+    // phi = phi o phi, that is u(x) = u(x) + u(x + u(x)).
+    for _ in 0..squarings {
+        let values = positions
+            .iter()
+            .zip(&nodes.values)
+            .map(|(p, u)| {
+                let q = Point3dd([p[0] + u[0], p[1] + u[1], p[2] + u[2]]);
+                let w = nodes.interpolate(&to_field.transform(&q));
+
+                [u[0] + w[0], u[1] + w[1], u[2] + w[2]]
+            })
+            .collect::<Vec<_>>();
+        nodes.values = values;
+    }
+
+    let displacements = nodes
+        .values
+        .iter()
+        .flat_map(|v| v.iter().map(|&c| c as f32))
+        .collect::<Vec<_>>();
+
+    GISTransform::from_grid(&grid, displacements)
+}
+
+impl GISTransform {
+    // Considering the values of the field as a stationary velocity field,
+    // in millimeters, the displacement field of its exponential, on the same
+    // grid.
+    pub fn exponential(&self) -> Result<GISTransform, Box<dyn Error>> {
+        exponential(self, &AffineTransform::identity(), 1.0)
+    }
+
+    // Exponential of the opposite velocity field, which is the inverse of
+    // `exponential`.
+    pub fn inverse_exponential(&self) -> Result<GISTransform, Box<dyn Error>> {
+        exponential(self, &AffineTransform::identity(), -1.0)
+    }
+}
+
+impl OrientedField {
+    // Same as `GISTransform::exponential`, velocities being expressed in
+    // world coordinates. DARTEL flow fields, whose velocities are in voxels,
+    // have to go through `from_voxel_units` first.
+    pub fn exponential(&self) -> Result<OrientedField, Box<dyn Error>> {
+        Ok(self.oriented(exponential(self.field(), self.to_world(), 1.0)?))
+    }
+
+    pub fn inverse_exponential(&self) -> Result<OrientedField, Box<dyn Error>> {
+        Ok(self.oriented(exponential(self.field(), self.to_world(), -1.0)?))
+    }
+
+    // Same field, its values being read as vectors in voxels of the field,
+    // such as the u_ flow fields of DARTEL, and moved to world coordinates.
+    pub fn from_voxel_units(&self) -> OrientedField {
+        let field = self.field();
+        let grid = field.grid();
+        let m = *voxel_to_world(&grid, self.to_world()).matrix();
+        let d = grid.dimensions;
+        let mut values = Vec::with_capacity(grid.len() * K);
+        for z in 0..d[2] {
+            for y in 0..d[1] {
+                for x in 0..d[0] {
+                    let v = field.ctrl_point(x, y, z);
+                    let v = [f64::from(v[0]), f64::from(v[1]), f64::from(v[2])];
+                    for w in matrix::apply(&m, &v).iter() {
+                        values.push(*w as f32);
+                    }
+                }
+            }
+        }
+
+        // The number of values matches the grid by construction.
+        let field = GISTransform::from_grid(&grid, values)
+            .unwrap()
+            .with_mode(field.mode());
+        self.oriented(field)
+    }
+
+    fn oriented(&self, field: GISTransform) -> OrientedField {
+        let w = self.to_world();
+
+        // The orientation was checked when building self.
+        OrientedField::new(field, AffineTransform::new(*w.matrix(), *w.offsets())).unwrap()
+    }
+}